@@ -0,0 +1,87 @@
+//! Throughput of allocation from many threads at once.
+//!
+//! Run with `cargo bench`. The `fake` rows measure the allocator itself, the `bind` rows include
+//! the real `bind` syscalls, and the `slow` rows model a probe, which blocks for a while, like
+//! `ConnectProbe` waiting for a timeout.
+
+use std::sync::Barrier;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use unique_port::PortAllocator;
+
+/// Ports handed out per round, which must fit into `1000..u16::MAX`.
+const PORTS_PER_ROUND: usize = 64_000;
+const ROUNDS: usize = 5;
+
+type Setup = fn() -> PortAllocator;
+
+fn run(threads: usize, allocator: impl Fn() -> PortAllocator, ports_per_round: usize) -> Duration {
+    let per_thread = ports_per_round / threads;
+    let mut total = Duration::default();
+    for _ in 0..ROUNDS {
+        let allocator = allocator();
+        let barrier = Barrier::new(threads);
+        let started = thread::scope(|scope| {
+            let handles = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        let started = Instant::now();
+                        for _ in 0..per_thread {
+                            allocator.get_unique_free_port().unwrap();
+                        }
+                        started
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .min()
+                .unwrap()
+        });
+        total += started.elapsed();
+    }
+    total / ROUNDS as u32
+}
+
+fn fake_probe() -> PortAllocator {
+    let allocator = PortAllocator::default();
+    allocator.set_exclude_ephemeral_ports(false).unwrap();
+    allocator.set_probe(|_: u16| true).unwrap();
+    allocator
+}
+
+fn slow_probe() -> PortAllocator {
+    let allocator = fake_probe();
+    allocator
+        .set_probe(|_: u16| {
+            thread::sleep(Duration::from_micros(100));
+            true
+        })
+        .unwrap();
+    allocator
+}
+
+fn bind_probe() -> PortAllocator {
+    let allocator = PortAllocator::default();
+    allocator.set_exclude_ephemeral_ports(false).unwrap();
+    allocator
+}
+
+fn main() {
+    println!("{:<8} {:>8} {:>14}", "probe", "threads", "ports/s");
+    let cases: [(&str, Setup, usize); 3] = [
+        ("fake", fake_probe, PORTS_PER_ROUND),
+        ("bind", bind_probe, PORTS_PER_ROUND / 8),
+        ("slow", slow_probe, PORTS_PER_ROUND / 64),
+    ];
+    for (name, allocator, ports) in cases.iter() {
+        for threads in [1, 4, 16, 64].iter() {
+            let elapsed = run(*threads, allocator, *ports);
+            let rate = *ports as f64 / elapsed.as_secs_f64();
+            println!("{:<8} {:>8} {:>14.0}", name, threads, rate);
+        }
+    }
+}
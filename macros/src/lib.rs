@@ -0,0 +1,151 @@
+//! Procedural macros for `unique_port`. Use them through the `macros` feature of `unique_port`.
+
+use proc_macro::TokenStream;
+use proc_macro2::Literal;
+use quote::quote;
+use syn::parse::Parser;
+use syn::punctuated::Punctuated;
+use syn::{
+    parse_macro_input, Error, Expr, ExprLit, FnArg, ItemFn, Lit, Meta, Token, Type, TypeArray,
+};
+
+/// Turns a function, which takes ports as arguments, into a test. See `unique_port::test`.
+#[proc_macro_attribute]
+pub fn test(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let function = parse_macro_input!(item as ItemFn);
+    match expand(attr.into(), function) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn expand(
+    attr: proc_macro2::TokenStream,
+    function: ItemFn,
+) -> syn::Result<proc_macro2::TokenStream> {
+    if let Some(asyncness) = &function.sig.asyncness {
+        return Err(Error::new_spanned(
+            asyncness,
+            "async test functions are not supported",
+        ));
+    }
+    let requested = parse_ports(attr)?;
+
+    // Ports go either all together into a single array argument, or one per argument
+    let (ports, array) = match array_argument(&function) {
+        Some(array) => match requested.or(array_len(array)) {
+            Some(ports) => (ports, true),
+            None => {
+                return Err(Error::new_spanned(
+                    array,
+                    "expected `ports = N` for an array of non-literal length",
+                ))
+            }
+        },
+        None => {
+            let arguments = function.sig.inputs.len();
+            match requested {
+                Some(ports) if ports != arguments => {
+                    return Err(Error::new_spanned(
+                        &function.sig.inputs,
+                        format!(
+                            "expected {} `u16` arguments or a single `[u16; {}]` argument",
+                            ports, ports
+                        ),
+                    ))
+                }
+                _ => (arguments, false),
+            }
+        }
+    };
+
+    let guards = (0..ports).map(|_| {
+        quote! {
+            ::unique_port::get_unique_free_port_guard()
+                .expect("Failed to allocate a unique port for the test")
+        }
+    });
+    let indices = 0..ports;
+    let len = Literal::usize_unsuffixed(ports);
+    let call_arguments = if array {
+        quote! { [#(__unique_port_guards[#indices].port()),*] }
+    } else {
+        quote! { #(__unique_port_guards[#indices].port()),* }
+    };
+
+    let attrs = &function.attrs;
+    let vis = &function.vis;
+    let name = &function.sig.ident;
+    let output = &function.sig.output;
+    let mut inner = function.clone();
+    inner.attrs.clear();
+    inner.vis = syn::Visibility::Inherited;
+
+    Ok(quote! {
+        #[test]
+        #(#attrs)*
+        #vis fn #name() #output {
+            #inner
+
+            // Guards are dropped on unwinding too, so the ports are released even if the test
+            // panics
+            let __unique_port_guards: [::unique_port::PortGuard<'static>; #len] = [#(#guards),*];
+            #name(#call_arguments)
+        }
+    })
+}
+
+/// Returns the type of the only argument of `function`, if it is an array.
+fn array_argument(function: &ItemFn) -> Option<&TypeArray> {
+    let mut inputs = function.sig.inputs.iter();
+    let argument = match (inputs.next(), inputs.next()) {
+        (Some(argument), None) => argument,
+        _ => return None,
+    };
+    let mut ty = match argument {
+        FnArg::Typed(pat_type) => &*pat_type.ty,
+        FnArg::Receiver(_) => return None,
+    };
+    // Types from `macro_rules!` may come wrapped into invisible groups
+    loop {
+        match ty {
+            Type::Group(group) => ty = &group.elem,
+            Type::Paren(paren) => ty = &paren.elem,
+            Type::Array(array) => return Some(array),
+            _ => return None,
+        }
+    }
+}
+
+/// Length of `array`, if it is an integer literal.
+fn array_len(array: &TypeArray) -> Option<usize> {
+    match &array.len {
+        Expr::Lit(ExprLit {
+            lit: Lit::Int(int), ..
+        }) => int.base10_parse().ok(),
+        _ => None,
+    }
+}
+
+/// Parses `ports = N` from the attribute arguments.
+fn parse_ports(attr: proc_macro2::TokenStream) -> syn::Result<Option<usize>> {
+    let metas = Punctuated::<Meta, Token![,]>::parse_terminated.parse2(attr)?;
+    let mut ports = None;
+    for meta in metas {
+        match meta {
+            Meta::NameValue(pair) if pair.path.is_ident("ports") => match &pair.value {
+                Expr::Lit(ExprLit {
+                    lit: Lit::Int(int), ..
+                }) => ports = Some(int.base10_parse()?),
+                value => return Err(Error::new_spanned(value, "expected an integer")),
+            },
+            other => {
+                return Err(Error::new_spanned(
+                    other,
+                    "unknown argument, expected `ports = N`",
+                ))
+            }
+        }
+    }
+    Ok(ports)
+}
@@ -0,0 +1,332 @@
+//! Port allocator with its own range, cursor and policies.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, TcpListener, UdpSocket};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+
+use crate::state::State;
+use crate::{
+    BindProbe, Config, Error, Lease, PortGuard, PortProbe, PortReservation, Protocol, Result,
+    WrapPolicy,
+};
+
+/// Common interface of the allocators, so that code handing out ports can be tested with
+/// [`crate::MockAllocator`].
+pub trait AllocatePort {
+    /// Returns a free port, which is unique for this allocator.
+    fn get_unique_free_port(&self) -> Result<u16>;
+}
+
+/// The global allocator, or the reason, why its settings couldn't be loaded.
+static GLOBAL: Lazy<Result<PortAllocator>> =
+    Lazy::new(|| Config::load().map(|config| PortAllocator::with_config(&config)));
+
+/// Hands out unique free ports. Ports are unique within one allocator, so independent subsystems
+/// can use separate allocators without disturbing each other's cursor. The free functions of the
+/// crate use the [`PortAllocator::global`] instance.
+///
+/// # Examples
+/// ```
+/// use unique_port::PortAllocator;
+///
+/// let allocator = PortAllocator::new(20000..21000);
+/// let port_1 = allocator.get_unique_free_port().unwrap();
+/// let port_2 = allocator.get_unique_free_port().unwrap();
+/// assert_ne!(port_1, port_2);
+/// assert!((20000..21000).contains(&port_1));
+/// ```
+///
+/// Concurrent calls never hand out the same port:
+/// ```
+/// use std::collections::HashSet;
+/// use unique_port::PortAllocator;
+///
+/// let allocator = PortAllocator::new(20000..21000);
+/// allocator.set_probe(|_: u16| true).unwrap();
+/// let allocate = || {
+///     (0..100)
+///         .map(|_| allocator.get_unique_free_port().unwrap())
+///         .collect::<Vec<_>>()
+/// };
+/// let ports = std::thread::scope(|scope| {
+///     let threads = (0..8).map(|_| scope.spawn(allocate)).collect::<Vec<_>>();
+///     threads
+///         .into_iter()
+///         .flat_map(|thread| thread.join().unwrap())
+///         .collect::<HashSet<_>>()
+/// });
+/// assert_eq!(800, ports.len());
+/// ```
+///
+/// A panic while allocating, e.g. in a custom probe, doesn't break the allocator for other
+/// threads:
+/// ```
+/// use std::panic::{catch_unwind, AssertUnwindSafe};
+/// use unique_port::PortAllocator;
+///
+/// let allocator = PortAllocator::new(20000..21000);
+/// allocator.set_probe(|_: u16| -> bool { panic!("probe failed") }).unwrap();
+/// assert!(catch_unwind(AssertUnwindSafe(|| allocator.get_unique_free_port())).is_err());
+///
+/// allocator.set_probe(|_: u16| true).unwrap();
+/// assert_eq!(20000, allocator.get_unique_free_port().unwrap());
+/// ```
+pub struct PortAllocator {
+    state: State,
+}
+
+impl Default for PortAllocator {
+    /// Allocator over `1000..u16::MAX`.
+    fn default() -> Self {
+        Self {
+            state: State::new(),
+        }
+    }
+}
+
+impl std::fmt::Debug for PortAllocator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PortAllocator").finish_non_exhaustive()
+    }
+}
+
+impl PortAllocator {
+    /// Creates an allocator, which hands out ports from `range`, starting from its lower bound.
+    pub fn new(range: Range<u16>) -> Self {
+        let state = State::new();
+        state.set_range(range.clone());
+        state.set_cursor(range.start);
+        Self { state }
+    }
+
+    /// Creates an allocator with the default settings, overridden by `config`.
+    ///
+    /// # Examples
+    /// ```
+    /// use unique_port::{Config, PortAllocator};
+    ///
+    /// let config = Config::parse_file("base = 20000").unwrap();
+    /// let allocator = PortAllocator::with_config(&config);
+    /// assert!(allocator.get_unique_free_port().unwrap() >= 20000);
+    /// ```
+    pub fn with_config(config: &Config) -> Self {
+        let state = State::new();
+        state.configure(config);
+        Self { state }
+    }
+
+    /// The allocator, used by the free functions of the crate. It is configured with
+    /// [`Config::load`] on first use.
+    ///
+    /// Fails on every call with the error of [`Config::load`], if the environment or the config
+    /// file has invalid settings, or the config file can't be read.
+    ///
+    /// # Examples
+    /// ```
+    /// use unique_port::{get_unique_free_port, set_port_index, Error};
+    ///
+    /// std::env::set_var("UNIQUE_PORT_RANGE", "bogus");
+    /// assert!(matches!(get_unique_free_port(), Err(Error::InvalidConfig(_))));
+    /// assert!(matches!(set_port_index(20000), Err(Error::InvalidConfig(_))));
+    /// ```
+    ///
+    /// ```
+    /// use unique_port::{get_unique_free_port, Error};
+    ///
+    /// // A directory can't be read as the config file
+    /// std::env::set_var("UNIQUE_PORT_CONFIG", std::env::temp_dir());
+    /// assert!(matches!(get_unique_free_port(), Err(Error::Io(_))));
+    /// assert!(matches!(get_unique_free_port(), Err(Error::Io(_))));
+    /// ```
+    pub fn global() -> Result<&'static Self> {
+        // Errors aren't `Clone`, so every caller gets an equal copy
+        GLOBAL.as_ref().map_err(|error| match error {
+            Error::Io(error) => Error::Io(match error.raw_os_error() {
+                Some(code) => io::Error::from_raw_os_error(code),
+                None => io::Error::new(error.kind(), error.to_string()),
+            }),
+            Error::InvalidConfig(message) => Error::InvalidConfig(message.clone()),
+            error => Error::InvalidConfig(error.to_string()),
+        })
+    }
+
+    /// See [`crate::set_port_index`].
+    pub fn set_port_index(&self, pindex: u16) -> Result<()> {
+        self.state.set_cursor(pindex);
+
+        Ok(())
+    }
+
+    /// See [`crate::set_port_range`].
+    pub fn set_port_range(&self, range: Range<u16>) -> Result<()> {
+        self.state.set_range(range);
+
+        Ok(())
+    }
+
+    /// Returns the range, which ports are handed out from.
+    ///
+    /// # Examples
+    /// ```
+    /// use unique_port::PortAllocator;
+    ///
+    /// assert_eq!(20000..21000, PortAllocator::new(20000..21000).port_range());
+    /// ```
+    pub fn port_range(&self) -> Range<u16> {
+        self.state.range()
+    }
+
+    /// See [`crate::set_exclude_ephemeral_ports`].
+    pub fn set_exclude_ephemeral_ports(&self, exclude: bool) -> Result<()> {
+        self.state.set_exclude_ephemeral(exclude);
+
+        Ok(())
+    }
+
+    /// See [`crate::set_wrap_policy`].
+    pub fn set_wrap_policy(&self, policy: WrapPolicy) -> Result<()> {
+        self.state.set_wrap(policy);
+
+        Ok(())
+    }
+
+    /// Sets the probe, which decides whether a port is free. It is [`BindProbe::default`] by
+    /// default. The probe is used by [`PortAllocator::get_unique_free_port`] and
+    /// [`PortAllocator::get_unique_free_port_block`], while the functions returning bound sockets
+    /// always bind for real.
+    pub fn set_probe(&self, probe: impl PortProbe + 'static) -> Result<()> {
+        self.state.set_probe(Arc::new(probe));
+
+        Ok(())
+    }
+
+    /// Returns a free unique port, which passes the probe of the allocator.
+    pub fn get_unique_free_port(&self) -> Result<u16> {
+        let probe = self.state.probe();
+        let (port, _) = self.state.allocate(|port| probe.probe(port))?;
+        Ok(port)
+    }
+
+    /// See [`crate::get_unique_free_port_guard`].
+    pub fn get_unique_free_port_guard(&self) -> Result<PortGuard<'_>> {
+        self.get_unique_free_port()
+            .map(|port| PortGuard::new(self, port))
+    }
+
+    /// See [`crate::lease_unique_free_port`].
+    pub fn lease_unique_free_port(&self, ttl: Duration) -> Result<Lease<'_>> {
+        let (port, id) = self.lease_port(ttl)?;
+        Ok(Lease::new(self, port, id, ttl))
+    }
+
+    /// Leases a port without tying the lease to a [`Lease`]. Returns the port and the lease id.
+    pub(crate) fn lease_port(&self, ttl: Duration) -> Result<(u16, u64)> {
+        // Checked before allocating, so that no port is lost
+        let expires = crate::state::expiry(ttl)?;
+        let port = self.get_unique_free_port()?;
+        Ok((port, self.state.lease(port, ttl, expires)))
+    }
+
+    pub(crate) fn renew_lease(&self, port: u16, id: u64) -> Result<()> {
+        self.state.renew(port, id)
+    }
+
+    pub(crate) fn end_lease(&self, port: u16, id: u64) {
+        self.state.end_lease(port, id)
+    }
+
+    /// See [`crate::release_port`].
+    pub fn release_port(&self, port: u16) -> Result<()> {
+        self.state.release(port);
+
+        Ok(())
+    }
+
+    /// See [`crate::get_unique_free_udp_port`].
+    pub fn get_unique_free_udp_port(&self) -> Result<u16> {
+        self.get_unique_free_port_for(Protocol::Udp)
+    }
+
+    /// See [`crate::get_unique_free_port_for`].
+    pub fn get_unique_free_port_for(&self, protocol: Protocol) -> Result<u16> {
+        self.get_unique_free_port_on(&[Ipv4Addr::LOCALHOST.into()], protocol)
+    }
+
+    /// See [`crate::get_unique_free_port_on`].
+    pub fn get_unique_free_port_on(&self, addrs: &[IpAddr], protocol: Protocol) -> Result<u16> {
+        let probe = BindProbe::new(addrs.to_vec(), protocol);
+        let (port, _) = self.state.allocate(|port| probe.probe(port))?;
+        Ok(port)
+    }
+
+    /// See [`crate::get_unique_free_port_block`].
+    pub fn get_unique_free_port_block(&self, len: u16) -> Result<Range<u16>> {
+        let probe = self.state.probe();
+        self.state.allocate_block(len, |port| probe.probe(port))
+    }
+
+    /// See [`crate::reserve_unique_free_port`].
+    pub fn reserve_unique_free_port(&self) -> Result<PortReservation> {
+        let (_, listener) = self
+            .state
+            .allocate(|port| TcpListener::bind((Ipv4Addr::LOCALHOST, port)))?;
+        PortReservation::new(listener)
+    }
+
+    /// See [`crate::get_unique_free_listener`].
+    pub fn get_unique_free_listener(&self) -> Result<TcpListener> {
+        Ok(self.reserve_unique_free_port()?.into_listener())
+    }
+
+    /// See [`crate::get_unique_free_udp_socket`].
+    pub fn get_unique_free_udp_socket(&self) -> Result<UdpSocket> {
+        let (_, socket) = self
+            .state
+            .allocate(|port| UdpSocket::bind((Ipv4Addr::LOCALHOST, port)))?;
+        Ok(socket)
+    }
+
+    /// See [`crate::get_unique_free_port_in`]. Ports, which other live processes hold in the
+    /// registry, are skipped, but the ones held by the current process may be handed out.
+    ///
+    /// # Examples
+    /// ```
+    /// use unique_port::registry::Registry;
+    /// use unique_port::PortAllocator;
+    ///
+    /// let path = std::env::temp_dir().join(format!("unique_port_in_{}", std::process::id()));
+    /// let registry = Registry::at(&path);
+    /// // Process 1 is alive, while the current one holds the next port already
+    /// std::fs::write(&path, format!("20000 1\n20001 {}\n", std::process::id())).unwrap();
+    ///
+    /// let allocator = PortAllocator::new(20000..20002);
+    /// assert_eq!(20001, allocator.get_unique_free_port_in(&registry).unwrap());
+    /// # std::fs::remove_file(&path).unwrap();
+    /// ```
+    #[cfg(unix)]
+    pub fn get_unique_free_port_in(&self, registry: &crate::registry::Registry) -> Result<u16> {
+        let probe = self.state.probe();
+        let pid = std::process::id();
+        registry.with_leases(|leases| {
+            let (port, _) = self.state.allocate(|port| match leases.get(&port) {
+                Some(owner) if *owner != pid => Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!("Port is leased by process {}", owner),
+                )),
+                _ => probe.probe(port),
+            })?;
+            leases.insert(port, pid);
+            Ok(port)
+        })?
+    }
+}
+
+impl AllocatePort for PortAllocator {
+    fn get_unique_free_port(&self) -> Result<u16> {
+        PortAllocator::get_unique_free_port(self)
+    }
+}
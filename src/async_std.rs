@@ -0,0 +1,51 @@
+//! Async variants of the allocation functions for the async-std runtime.
+//!
+//! Probing performs blocking `bind` calls, so it runs on the blocking thread pool. The allocator
+//! is lock-free, so concurrent calls probe in parallel there.
+
+use ::async_std::net::{TcpListener, UdpSocket};
+use ::async_std::task;
+
+use crate::Result;
+
+async fn spawn_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    task::spawn_blocking(f).await
+}
+
+/// Async version of [`crate::get_unique_free_port`].
+///
+/// # Examples
+/// ```
+/// async_std::task::block_on(async {
+///     let port_1 = unique_port::async_std::get_unique_free_port().await.unwrap();
+///     let port_2 = unique_port::async_std::get_unique_free_port().await.unwrap();
+///     assert_ne!(port_1, port_2);
+/// });
+/// ```
+pub async fn get_unique_free_port() -> Result<u16> {
+    spawn_blocking(crate::get_unique_free_port).await
+}
+
+/// Async version of [`crate::get_unique_free_listener`], which returns an async-std listener.
+///
+/// # Examples
+/// ```
+/// async_std::task::block_on(async {
+///     let listener = unique_port::async_std::get_unique_free_listener().await.unwrap();
+///     assert!(listener.local_addr().unwrap().ip().is_loopback());
+/// });
+/// ```
+pub async fn get_unique_free_listener() -> Result<TcpListener> {
+    Ok(spawn_blocking(crate::get_unique_free_listener)
+        .await?
+        .into())
+}
+
+/// Async version of [`crate::get_unique_free_udp_socket`], which returns an async-std socket.
+pub async fn get_unique_free_udp_socket() -> Result<UdpSocket> {
+    Ok(spawn_blocking(crate::get_unique_free_udp_socket)
+        .await?
+        .into())
+}
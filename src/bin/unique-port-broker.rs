@@ -0,0 +1,60 @@
+//! Port broker daemon. See `unique_port::broker` for the protocol.
+//!
+//! Usage: `unique-port-broker [--socket PATH] [--ttl SECS] [--range START..END]`
+
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+use unique_port::broker::{self, Broker};
+use unique_port::config::{self, Config};
+use unique_port::PortAllocator;
+
+const USAGE: &str = "Usage: unique-port-broker [--socket PATH] [--ttl SECS] [--range START..END]";
+
+fn main() {
+    if let Err(error) = run() {
+        eprintln!("unique-port-broker: {}", error);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), String> {
+    let mut socket = broker::default_socket_path();
+    let mut ttl = broker::DEFAULT_TTL;
+    let mut config = Config::load().map_err(|e| e.to_string())?;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("`{}` needs a value", arg))
+        };
+        match arg.as_str() {
+            "--socket" => socket = PathBuf::from(value()?),
+            "--ttl" => {
+                let secs = value()?;
+                ttl = secs
+                    .parse()
+                    .ok()
+                    .filter(|secs| *secs > 0)
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| format!("Invalid TTL `{}`", secs))?;
+            }
+            "--range" => {
+                let range = value()?;
+                config.range = Some(config::parse_range(&range).map_err(|e| e.to_string())?);
+            }
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ => return Err(format!("Unknown argument `{}`\n{}", arg, USAGE)),
+        }
+    }
+
+    let broker = Broker::new(PortAllocator::with_config(&config), ttl);
+    let listener = broker.bind(&socket).map_err(|e| e.to_string())?;
+    eprintln!("Listening on {}", socket.display());
+    broker.serve(listener).map_err(|e| e.to_string())
+}
@@ -0,0 +1,185 @@
+//! Command-line access to the allocator, for shell scripts and CI.
+//!
+//! Ports are unique within one invocation. To keep them unique across invocations, run
+//! `unique-port-broker` and set `UNIQUE_PORT_BROKER`, so that `get` leases TCP ports from it for
+//! the default TTL of the broker. The JSON output has the lease tokens then, for renewing or
+//! releasing the leases. `--udp` and `--contiguous` aren't supported by the broker, so they fail
+//! with it.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::process;
+
+use unique_port::{ephemeral_port_range, BindProbe, PortAllocator, Protocol};
+
+const USAGE: &str = "\
+Usage: unique-port [--json] <command>
+
+Commands:
+    get [--count N] [--udp] [--contiguous]    Prints free unique ports
+    check [--udp] <port>                      Checks whether a port is free
+    range                                     Prints the allocation and ephemeral ranges";
+
+fn main() {
+    match run() {
+        Ok(code) => process::exit(code),
+        Err(error) => {
+            eprintln!("unique-port: {}", error);
+            process::exit(2);
+        }
+    }
+}
+
+/// Returns the exit code: `0` on success, `1` if `check` finds the port in use.
+fn run() -> Result<i32, String> {
+    let mut json = false;
+    let mut count = 1;
+    let mut protocol = Protocol::Tcp;
+    let mut contiguous = false;
+    let mut positional = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--udp" => protocol = Protocol::Udp,
+            "--contiguous" => contiguous = true,
+            "--count" | "-n" => {
+                let value = args.next().ok_or("`--count` needs a value")?;
+                count = value
+                    .parse()
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .ok_or_else(|| format!("Invalid count `{}`", value))?;
+            }
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return Ok(0);
+            }
+            _ if arg.starts_with('-') => {
+                return Err(format!("Unknown option `{}`\n{}", arg, USAGE))
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    match positional
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["get"] => get(count, protocol, contiguous, json),
+        ["check", port] => {
+            let port = port
+                .parse()
+                .map_err(|_| format!("Invalid port `{}`", port))?;
+            check(port, protocol, json)
+        }
+        ["range"] => range(json),
+        _ => Err(USAGE.to_owned()),
+    }
+}
+
+fn get(count: u16, protocol: Protocol, contiguous: bool, json: bool) -> Result<i32, String> {
+    #[cfg(unix)]
+    if let Some(socket) = std::env::var_os(unique_port::broker::BROKER_ENV) {
+        if protocol != Protocol::Tcp || contiguous {
+            return Err(format!(
+                "`--udp` and `--contiguous` can't be used with `{}`",
+                unique_port::broker::BROKER_ENV
+            ));
+        }
+        return get_from_broker(socket.into(), count, json);
+    }
+
+    let allocator = PortAllocator::global().map_err(|e| e.to_string())?;
+    if protocol != Protocol::Tcp {
+        allocator
+            .set_probe(BindProbe::new(vec![Ipv4Addr::LOCALHOST.into()], protocol))
+            .map_err(|e| e.to_string())?;
+    }
+    let ports = if contiguous {
+        allocator
+            .get_unique_free_port_block(count)
+            .map_err(|e| e.to_string())?
+            .collect()
+    } else {
+        (0..count)
+            .map(|_| unique_port::get_unique_free_port())
+            .collect::<unique_port::Result<Vec<_>>>()
+            .map_err(|e| e.to_string())?
+    };
+
+    if json {
+        println!("{{\"ports\":[{}]}}", join(&ports));
+    } else {
+        ports.iter().for_each(|port| println!("{}", port));
+    }
+    Ok(0)
+}
+
+#[cfg(unix)]
+fn get_from_broker(socket: std::path::PathBuf, count: u16, json: bool) -> Result<i32, String> {
+    let client = unique_port::broker::Client::new(socket);
+    let tickets = (0..count)
+        .map(|_| client.lease(None))
+        .collect::<unique_port::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+
+    if json {
+        let ports = tickets.iter().map(|ticket| ticket.port).collect::<Vec<_>>();
+        let tokens = tickets.iter().map(|ticket| ticket.id).collect::<Vec<_>>();
+        println!(
+            "{{\"ports\":[{}],\"tokens\":[{}]}}",
+            join(&ports),
+            join(&tokens)
+        );
+    } else {
+        tickets
+            .iter()
+            .for_each(|ticket| println!("{}", ticket.port));
+    }
+    Ok(0)
+}
+
+fn join<T: ToString>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn check(port: u16, protocol: Protocol, json: bool) -> Result<i32, String> {
+    let free = protocol
+        .probe(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+        .is_ok();
+    if json {
+        println!("{{\"port\":{},\"free\":{}}}", port, free);
+    } else {
+        println!("{}", if free { "free" } else { "in use" });
+    }
+    Ok(if free { 0 } else { 1 })
+}
+
+fn range(json: bool) -> Result<i32, String> {
+    let range = PortAllocator::global()
+        .map_err(|e| e.to_string())?
+        .port_range();
+    let ephemeral = ephemeral_port_range();
+    if json {
+        let ephemeral = ephemeral.map_or("null".to_owned(), |range| {
+            format!("{{\"start\":{},\"end\":{}}}", range.start, range.end)
+        });
+        println!(
+            "{{\"range\":{{\"start\":{},\"end\":{}}},\"ephemeral\":{}}}",
+            range.start, range.end, ephemeral
+        );
+    } else {
+        println!("range {}..{}", range.start, range.end);
+        if let Some(ephemeral) = ephemeral {
+            println!("ephemeral {}..{}", ephemeral.start, ephemeral.end);
+        }
+    }
+    Ok(0)
+}
@@ -0,0 +1,345 @@
+//! Port broker, which owns the allocation state for all processes on the machine, regardless of
+//! their language.
+//!
+//! The broker listens on a Unix domain socket. Each request and response is one line of text:
+//!
+//! | Request                  | Response               |
+//! |--------------------------|------------------------|
+//! | `LEASE [ttl_secs]`       | `OK <port> <token>`    |
+//! | `RENEW <port> <token>`   | `OK`                   |
+//! | `RELEASE <port> <token>` | `OK`                   |
+//!
+//! Failures are reported as `ERR <message>`. A lease, which isn't renewed within its TTL, expires
+//! and its port may be handed out again. The token tells the leases of the same port apart, so a
+//! client, whose lease has expired, can't renew or release the lease of whoever got the port next.
+//!
+//! When `UNIQUE_PORT_BROKER` is set to the socket path, [`crate::get_unique_free_port`],
+//! [`crate::get_unique_free_port_guard`] (and so `#[unique_port::test]`) and
+//! [`crate::release_port`] go through the broker instead of the in-process allocator. The leases
+//! they take are renewed in the background for as long as the process runs, so the ports stay
+//! unique during the whole run, and are reclaimed soon after the process exits. The other free
+//! functions, which allocate ports, fail with [`Error::Broker`] then, as the broker only hands out
+//! single TCP ports.
+//!
+//! ```sh
+//! $ unique-port-broker --socket /tmp/ports.sock &
+//! $ echo LEASE | nc -U /tmp/ports.sock
+//! OK 1000 0
+//! ```
+//!
+//! ```
+//! use std::time::Duration;
+//! use unique_port::broker::{Broker, BROKER_ENV};
+//! use unique_port::PortAllocator;
+//!
+//! let socket = std::env::temp_dir().join(format!("unique_port_env_{}.sock", std::process::id()));
+//! let broker = Broker::new(PortAllocator::new(20000..21000), Duration::from_secs(60));
+//! let listener = broker.bind(&socket).unwrap();
+//! std::thread::spawn(move || broker.serve(listener));
+//! std::env::set_var(BROKER_ENV, &socket);
+//!
+//! let port = unique_port::get_unique_free_port().unwrap();
+//! assert!((20000..21000).contains(&port));
+//! unique_port::release_port(port).unwrap();
+//!
+//! // Released ports go back to the broker, so it hands them out again
+//! let guard = unique_port::get_unique_free_port_guard().unwrap();
+//! assert_eq!(port, guard.port());
+//! drop(guard);
+//! assert_eq!(port, unique_port::get_unique_free_port().unwrap());
+//!
+//! assert!(unique_port::get_unique_free_udp_port().is_err());
+//! # std::fs::remove_file(&socket).unwrap();
+//! ```
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, Once, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+
+use crate::{Error, PortAllocator, Result};
+
+/// Environment variable with the socket path of the broker, which the free functions should use.
+pub const BROKER_ENV: &str = "UNIQUE_PORT_BROKER";
+
+/// TTL of leases, for which the client didn't ask for a specific one.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+/// TTL of the leases, which the free functions take and renew in the background.
+const HELD_TTL: Duration = Duration::from_secs(30);
+
+/// Leases taken by the free functions, with the socket paths of their brokers.
+static HELD: Lazy<Mutex<HashMap<u16, (PathBuf, Ticket)>>> = Lazy::new(Default::default);
+
+/// Socket path, which the broker binary listens on by default.
+pub fn default_socket_path() -> PathBuf {
+    env::temp_dir().join("unique_port.sock")
+}
+
+/// Socket path from [`BROKER_ENV`], if it is set.
+pub(crate) fn from_env() -> Option<PathBuf> {
+    env::var_os(BROKER_ENV).map(PathBuf::from)
+}
+
+fn held() -> MutexGuard<'static, HashMap<u16, (PathBuf, Ticket)>> {
+    HELD.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Leases a port from the broker at `path` for the rest of the run. See
+/// [`crate::get_unique_free_port`].
+pub(crate) fn lease_held(path: PathBuf) -> Result<u16> {
+    static RENEWAL: Once = Once::new();
+    RENEWAL.call_once(|| {
+        thread::spawn(renew_held);
+    });
+    let ticket = Client::new(&path).lease(Some(HELD_TTL))?;
+    held().insert(ticket.port, (path, ticket));
+    Ok(ticket.port)
+}
+
+/// Gives a port, leased by [`lease_held`], back to its broker. Other ports are ignored.
+pub(crate) fn release_held(port: u16) -> Result<()> {
+    match held().remove(&port) {
+        Some((path, ticket)) => Client::new(path).release(ticket),
+        None => Ok(()),
+    }
+}
+
+/// Renews the held leases well before they expire. Leases, which the broker rejects, are dropped,
+/// while I/O errors are retried, as the broker may be restarting.
+fn renew_held() {
+    loop {
+        thread::sleep(HELD_TTL / 3);
+        let leases = held().values().cloned().collect::<Vec<_>>();
+        for (path, ticket) in leases {
+            if let Err(Error::Broker(_)) = Client::new(path).renew(ticket) {
+                held().remove(&ticket.port);
+            }
+        }
+    }
+}
+
+/// Hands out ports from its allocator to clients over a Unix domain socket.
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use unique_port::broker::{Broker, Client};
+/// use unique_port::PortAllocator;
+///
+/// let socket = std::env::temp_dir().join(format!("unique_port_doc_{}.sock", std::process::id()));
+/// let broker = Broker::new(PortAllocator::new(20000..21000), Duration::from_secs(60));
+/// let listener = broker.bind(&socket).unwrap();
+/// std::thread::spawn(move || broker.serve(listener));
+///
+/// let client = Client::new(&socket);
+/// let ticket = client.lease(None).unwrap();
+/// assert!((20000..21000).contains(&ticket.port));
+/// client.renew(ticket).unwrap();
+/// client.release(ticket).unwrap();
+/// assert!(client.renew(ticket).is_err());
+///
+/// // Too long TTLs are rejected without taking a port
+/// assert!(client.lease(Some(Duration::MAX)).is_err());
+/// assert_eq!(ticket.port, client.lease(None).unwrap().port);
+/// # std::fs::remove_file(&socket).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Broker {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    allocator: PortAllocator,
+    ttl: Duration,
+}
+
+impl Broker {
+    /// Creates a broker, handing out ports from `allocator` with leases of `ttl` by default.
+    pub fn new(allocator: PortAllocator, ttl: Duration) -> Self {
+        Self {
+            inner: Arc::new(Inner { allocator, ttl }),
+        }
+    }
+
+    /// Binds the socket at `path`, replacing a stale socket file left by a previous broker. Other
+    /// files at `path` are left alone, and binding fails then.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    /// use unique_port::broker::Broker;
+    /// use unique_port::PortAllocator;
+    ///
+    /// let path = std::env::temp_dir().join(format!("unique_port_doc_{}.txt", std::process::id()));
+    /// std::fs::write(&path, "notes").unwrap();
+    /// let broker = Broker::new(PortAllocator::new(20000..21000), Duration::from_secs(60));
+    /// assert!(broker.bind(&path).is_err());
+    /// assert_eq!("notes", std::fs::read_to_string(&path).unwrap());
+    /// # std::fs::remove_file(&path).unwrap();
+    /// ```
+    pub fn bind(&self, path: &Path) -> Result<UnixListener> {
+        let is_socket = fs::symlink_metadata(path)
+            .map(|metadata| metadata.file_type().is_socket())
+            .unwrap_or(false);
+        if is_socket && UnixStream::connect(path).is_err() {
+            fs::remove_file(path)?;
+        }
+        Ok(UnixListener::bind(path)?)
+    }
+
+    /// Serves the clients of `listener` forever, one thread per connection.
+    pub fn serve(&self, listener: UnixListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let broker = self.clone();
+            thread::spawn(move || broker.handle(stream));
+        }
+        Ok(())
+    }
+
+    /// Handles the requests of one connection. Errors just close the connection.
+    fn handle(&self, stream: UnixStream) -> Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let response = match self.respond(&line?) {
+                Ok(response) => response,
+                Err(Error::Broker(message)) => format!("ERR {}", message),
+                Err(error) => format!("ERR {}", error),
+            };
+            writeln!(writer, "{}", response)?;
+        }
+        Ok(())
+    }
+
+    fn respond(&self, request: &str) -> Result<String> {
+        let mut words = request.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let arguments = words
+            .map(|word| {
+                word.parse::<u64>()
+                    .map_err(|_| Error::Broker(format!("Invalid argument `{}`", word)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // Leases are tracked by the allocator, with the lease id as the token
+        let ticket = || -> Result<Ticket> {
+            match *arguments.as_slice() {
+                [port, id] => Ok(Ticket {
+                    port: u16::try_from(port)
+                        .map_err(|_| Error::Broker(format!("Invalid port `{}`", port)))?,
+                    id,
+                }),
+                _ => Err(Error::Broker(format!(
+                    "`{}` expects a port and a token",
+                    command
+                ))),
+            }
+        };
+        match command {
+            "LEASE" => {
+                let ttl = match *arguments.as_slice() {
+                    [] => self.inner.ttl,
+                    [0] => return Err(Error::Broker("TTL must be positive".to_owned())),
+                    [secs] => Duration::from_secs(secs),
+                    _ => return Err(Error::Broker("`LEASE` expects a TTL".to_owned())),
+                };
+                let (port, id) = self.inner.allocator.lease_port(ttl)?;
+                Ok(format!("OK {} {}", port, id))
+            }
+            "RENEW" => {
+                let ticket = ticket()?;
+                self.inner.allocator.renew_lease(ticket.port, ticket.id)?;
+                Ok("OK".to_owned())
+            }
+            "RELEASE" => {
+                let ticket = ticket()?;
+                self.inner.allocator.end_lease(ticket.port, ticket.id);
+                Ok("OK".to_owned())
+            }
+            _ => Err(Error::Broker(format!("Unknown command `{}`", command))),
+        }
+    }
+}
+
+/// Port leased from a [`Broker`], together with the token of the lease.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ticket {
+    /// The leased port.
+    pub port: u16,
+    /// Token, which identifies the lease in renewals and releases.
+    pub id: u64,
+}
+
+/// Client of a [`Broker`]. Every request uses its own connection.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Client {
+    path: PathBuf,
+}
+
+impl Client {
+    /// Client of the broker listening on `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Leases a port for `ttl`, or for the default TTL of the broker. The TTL is rounded up to
+    /// whole seconds, and must not be zero.
+    pub fn lease(&self, ttl: Option<Duration>) -> Result<Ticket> {
+        let request = match ttl {
+            Some(ttl) if ttl == Duration::from_secs(0) => {
+                return Err(Error::Broker("TTL must be positive".to_owned()))
+            }
+            Some(ttl) => {
+                let secs = ttl
+                    .as_secs()
+                    .saturating_add(u64::from(ttl.subsec_nanos() > 0));
+                format!("LEASE {}", secs)
+            }
+            None => "LEASE".to_owned(),
+        };
+        let response = self.request(&request)?;
+        let unexpected = || Error::Broker(format!("Unexpected response `{}`", response));
+        let (port, id) = response.split_once(' ').ok_or_else(unexpected)?;
+        Ok(Ticket {
+            port: port.parse().map_err(|_| unexpected())?,
+            id: id.parse().map_err(|_| unexpected())?,
+        })
+    }
+
+    /// Extends the lease of `ticket` by its TTL.
+    pub fn renew(&self, ticket: Ticket) -> Result<()> {
+        self.request(&format!("RENEW {} {}", ticket.port, ticket.id))
+            .map(drop)
+    }
+
+    /// Gives the port of `ticket` back to the broker. Does nothing, if the lease has expired.
+    pub fn release(&self, ticket: Ticket) -> Result<()> {
+        self.request(&format!("RELEASE {} {}", ticket.port, ticket.id))
+            .map(drop)
+    }
+
+    /// Sends one request and returns the response without the `OK` prefix.
+    fn request(&self, request: &str) -> Result<String> {
+        let mut stream = UnixStream::connect(&self.path)?;
+        writeln!(stream, "{}", request)?;
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response)?;
+        let response = response.trim_end();
+        match response.split_once(' ') {
+            Some(("ERR", message)) => Err(Error::Broker(message.to_owned())),
+            Some(("OK", rest)) => Ok(rest.to_owned()),
+            None if response == "OK" => Ok(String::new()),
+            _ => Err(Error::Broker(format!("Unexpected response `{}`", response))),
+        }
+    }
+}
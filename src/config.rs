@@ -0,0 +1,227 @@
+//! Allocation settings, which can be changed without recompiling.
+//!
+//! Settings are looked up in this order, the first found wins:
+//!
+//! 1. Environment variables `UNIQUE_PORT_RANGE`, `UNIQUE_PORT_BASE` and `UNIQUE_PORT_STRATEGY`.
+//! 2. The config file at `UNIQUE_PORT_CONFIG`, or `unique_port.toml` in the current directory if
+//!    the variable isn't set. It may contain the keys `range`, `base` and `strategy`.
+//! 3. The built-in defaults.
+//!
+//! Functions like [`crate::set_port_range`], called at runtime, override all of them.
+//!
+//! ```toml
+//! # unique_port.toml
+//! range = "20000..30000"
+//! base = 21000
+//! strategy = "both"
+//! ```
+
+use std::env;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::{BindProbe, ConnectProbe, Error, PortProbe, Protocol, Result};
+
+/// Name of the config file, which is looked up in the current directory.
+pub const CONFIG_FILE_NAME: &str = "unique_port.toml";
+
+/// How ports are checked for being free. See [`crate::PortProbe`] for the implementations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Strategy {
+    /// `tcp`: [`BindProbe`] with [`Protocol::Tcp`].
+    Tcp,
+    /// `udp`: [`BindProbe`] with [`Protocol::Udp`].
+    Udp,
+    /// `both`: [`BindProbe`] with [`Protocol::Both`].
+    Both,
+    /// `connect`: [`ConnectProbe`].
+    Connect,
+    /// `procnet`: [`crate::ProcNetProbe`], which is available on Linux only.
+    #[cfg(target_os = "linux")]
+    ProcNet,
+}
+
+impl FromStr for Strategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tcp" => Ok(Self::Tcp),
+            "udp" => Ok(Self::Udp),
+            "both" => Ok(Self::Both),
+            "connect" => Ok(Self::Connect),
+            #[cfg(target_os = "linux")]
+            "procnet" => Ok(Self::ProcNet),
+            _ => Err(Error::InvalidConfig(format!("Unknown strategy `{}`", s))),
+        }
+    }
+}
+
+impl Strategy {
+    pub(crate) fn probe(self) -> Arc<dyn PortProbe> {
+        let localhost = vec![std::net::Ipv4Addr::LOCALHOST.into()];
+        match self {
+            Self::Tcp => Arc::new(BindProbe::new(localhost, Protocol::Tcp)),
+            Self::Udp => Arc::new(BindProbe::new(localhost, Protocol::Udp)),
+            Self::Both => Arc::new(BindProbe::new(localhost, Protocol::Both)),
+            Self::Connect => Arc::new(ConnectProbe::default()),
+            #[cfg(target_os = "linux")]
+            Self::ProcNet => Arc::new(crate::ProcNetProbe),
+        }
+    }
+}
+
+/// Settings of an allocator. Unset ones keep their defaults.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct Config {
+    /// Ports to hand out. `1000..u16::MAX` by default.
+    pub range: Option<Range<u16>>,
+    /// Port, from which allocation starts. Without `range`, it is also the lower bound of the
+    /// range.
+    pub base: Option<u16>,
+    /// How ports are checked for being free. [`Strategy::Tcp`] by default.
+    pub strategy: Option<Strategy>,
+}
+
+impl Config {
+    /// Loads the settings from the environment and the config file, as described in the
+    /// [module docs](self).
+    ///
+    /// # Examples
+    /// ```
+    /// use unique_port::Config;
+    ///
+    /// std::env::set_var("UNIQUE_PORT_RANGE", "20000..30000");
+    /// let config = Config::load().unwrap();
+    /// assert_eq!(Some(20000..30000), config.range);
+    /// ```
+    pub fn load() -> Result<Self> {
+        let mut config = Self::from_env()?;
+        let path = env::var_os("UNIQUE_PORT_CONFIG")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(CONFIG_FILE_NAME));
+        match fs::read_to_string(&path) {
+            Ok(contents) => config = config.or(Self::parse_file(&contents)?),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+        config.validate()
+    }
+
+    /// Reads only the environment variables.
+    pub fn from_env() -> Result<Self> {
+        let var = |name| env::var(name).ok();
+        Self {
+            range: var("UNIQUE_PORT_RANGE")
+                .map(|value| parse_range(&value))
+                .transpose()?,
+            base: var("UNIQUE_PORT_BASE")
+                .map(|value| parse_port(&value))
+                .transpose()?,
+            strategy: var("UNIQUE_PORT_STRATEGY")
+                .map(|value| value.parse())
+                .transpose()?,
+        }
+        .validate()
+    }
+
+    /// Parses the config file contents. It is a flat list of `key = value` lines, which is a subset
+    /// of TOML. Values may be quoted.
+    ///
+    /// # Examples
+    /// ```
+    /// use unique_port::{Config, Strategy};
+    ///
+    /// let config = Config::parse_file("base = 20000 # CI firewall\nstrategy = \"both\"").unwrap();
+    /// assert_eq!(Some(20000), config.base);
+    /// assert_eq!(Some(Strategy::Both), config.strategy);
+    ///
+    /// assert!(Config::parse_file("range = \"30000..20000\"").is_err());
+    /// assert!(Config::parse_file("range = \"20000..30000\"\nbase = 40000").is_err());
+    /// ```
+    pub fn parse_file(contents: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim().trim_matches('"')),
+                None => {
+                    return Err(Error::InvalidConfig(format!(
+                        "Expected `key = value`, got `{}`",
+                        line
+                    )))
+                }
+            };
+            match key {
+                "range" => config.range = Some(parse_range(value)?),
+                "base" => config.base = Some(parse_port(value)?),
+                "strategy" => config.strategy = Some(value.parse()?),
+                _ => {
+                    return Err(Error::InvalidConfig(format!(
+                        "Unknown config key `{}`",
+                        key
+                    )))
+                }
+            }
+        }
+        config.validate()
+    }
+
+    /// Fails if `base` is outside of `range`, as nothing could be allocated then.
+    fn validate(self) -> Result<Self> {
+        match (&self.range, self.base) {
+            (Some(range), Some(base)) if !range.contains(&base) => {
+                Err(Error::InvalidConfig(format!(
+                    "Base {} is outside of range {}..{}",
+                    base, range.start, range.end
+                )))
+            }
+            _ => Ok(self),
+        }
+    }
+
+    /// Fills the unset settings from `other`.
+    fn or(self, other: Self) -> Self {
+        Self {
+            range: self.range.or(other.range),
+            base: self.base.or(other.base),
+            strategy: self.strategy.or(other.strategy),
+        }
+    }
+}
+
+/// Parses `start..end` or `start-end`, where the end is exclusive in both cases and must be
+/// greater than the start. This is the format of `range` in the config file.
+///
+/// # Examples
+/// ```
+/// use unique_port::config::parse_range;
+///
+/// assert_eq!(20000..30000, parse_range("20000-30000").unwrap());
+/// assert!(parse_range("30000..20000").is_err());
+/// ```
+pub fn parse_range(value: &str) -> Result<Range<u16>> {
+    let (start, end) = value
+        .split_once("..")
+        .or_else(|| value.split_once('-'))
+        .ok_or_else(|| Error::InvalidConfig(format!("Expected `start..end`, got `{}`", value)))?;
+    let range = parse_port(start)?..parse_port(end)?;
+    if range.is_empty() {
+        return Err(Error::InvalidConfig(format!("Empty range `{}`", value)));
+    }
+    Ok(range)
+}
+
+fn parse_port(value: &str) -> Result<u16> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| Error::InvalidConfig(format!("Invalid port `{}`", value)))
+}
@@ -0,0 +1,67 @@
+//! Kernel ephemeral port range, from which the OS picks ports for outgoing connections. Ports
+//! from it may be taken by any client socket at any moment, so they are not handed out by default.
+
+use std::ops::{Range, RangeInclusive};
+
+use crate::state::PortSet;
+
+/// Returns the ephemeral port range of the kernel, if it is known on this platform.
+///
+/// # Examples
+/// ```
+/// use unique_port::ephemeral_port_range;
+///
+/// if let Some(range) = ephemeral_port_range() {
+///     assert!(range.start < range.end);
+/// }
+/// ```
+pub fn ephemeral_port_range() -> Option<Range<u16>> {
+    #[cfg(target_os = "linux")]
+    {
+        let contents = std::fs::read_to_string("/proc/sys/net/ipv4/ip_local_port_range").ok()?;
+        let mut bounds = contents.split_whitespace().map(str::parse::<u16>);
+        let start = bounds.next()?.ok()?;
+        let end = bounds.next()?.ok()?;
+        Some(start..end.saturating_add(1))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+/// Ports from the ephemeral range, which the kernel doesn't reserve for explicit binds.
+pub(crate) fn ephemeral_ports() -> PortSet {
+    let mut ports = PortSet::new();
+    if let Some(range) = ephemeral_port_range() {
+        range.for_each(|port| ports.insert(port));
+    }
+    reserved_ports()
+        .into_iter()
+        .flatten()
+        .for_each(|port| ports.remove(port));
+    ports
+}
+
+/// Ports from `ip_local_reserved_ports`, which the kernel never picks as ephemeral ones.
+fn reserved_ports() -> Vec<RangeInclusive<u16>> {
+    #[cfg(target_os = "linux")]
+    let contents =
+        std::fs::read_to_string("/proc/sys/net/ipv4/ip_local_reserved_ports").unwrap_or_default();
+    #[cfg(not(target_os = "linux"))]
+    let contents = String::new();
+
+    contents
+        .trim()
+        .split(',')
+        .filter_map(|item| {
+            let mut bounds = item.trim().splitn(2, '-').map(str::parse::<u16>);
+            let start = bounds.next()?.ok()?;
+            let end = match bounds.next() {
+                Some(end) => end.ok()?,
+                None => start,
+            };
+            Some(start..=end)
+        })
+        .collect()
+}
@@ -0,0 +1,77 @@
+//! Error type of the crate.
+
+use std::fmt;
+use std::io;
+use std::ops::Range;
+
+/// Result with [`Error`] as the default error type.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors, which may occur while allocating ports. New variants may be added in minor releases.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// No free port is left in the scanned range.
+    RangeExhausted {
+        /// The scanned range.
+        range: Range<u16>,
+        /// The error of the last failed bind, if any port was tried at all. On Linux, it names the
+        /// processes holding the port, if it was in use.
+        last_error: Option<io::Error>,
+    },
+    /// An I/O operation other than probing a port has failed.
+    Io(io::Error),
+    /// A setting from the environment or the config file is invalid.
+    InvalidConfig(String),
+    /// A scripted [`crate::MockAllocator`] has handed out all of its ports.
+    ScriptExhausted,
+    /// An argument is out of the accepted values.
+    InvalidArgument(String),
+    /// The port broker has rejected a request or responded unexpectedly.
+    Broker(String),
+    /// The lease of the port has expired, and the port may have been handed out again.
+    LeaseExpired(u16),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RangeExhausted { range, last_error } => {
+                write!(
+                    f,
+                    "Failed to get empty port in range {}..{}",
+                    range.start, range.end
+                )?;
+                match last_error {
+                    Some(error) => write!(f, ": {}", error),
+                    None => Ok(()),
+                }
+            }
+            Self::Io(error) => write!(f, "I/O error: {}", error),
+            Self::InvalidConfig(message) => write!(f, "Invalid configuration: {}", message),
+            Self::ScriptExhausted => write!(f, "The scripted mock allocator has no ports left"),
+            Self::InvalidArgument(message) => write!(f, "Invalid argument: {}", message),
+            Self::Broker(message) => write!(f, "Broker error: {}", message),
+            Self::LeaseExpired(port) => write!(f, "Lease of port {} has expired", port),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::RangeExhausted {
+                last_error: Some(error),
+                ..
+            }
+            | Self::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
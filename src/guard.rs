@@ -0,0 +1,81 @@
+//! Guard, which gives its port back to the allocator when dropped.
+
+use std::fmt;
+
+use crate::PortAllocator;
+
+/// A unique port, which is put back into the free list of its allocator (see
+/// [`crate::release_port`]) when the guard is dropped, so that it can be handed out again.
+///
+/// # Examples
+/// ```
+/// use unique_port::get_unique_free_port_guard;
+///
+/// let port = {
+///     let guard = get_unique_free_port_guard().unwrap();
+///     guard.port()
+/// };
+/// assert_eq!(port, get_unique_free_port_guard().unwrap().port());
+/// ```
+#[derive(Debug)]
+pub struct PortGuard<'a> {
+    owner: Owner<'a>,
+    port: u16,
+}
+
+/// Where the port goes back to.
+#[derive(Debug)]
+enum Owner<'a> {
+    Allocator(&'a PortAllocator),
+    /// The broker, which [`crate::get_unique_free_port_guard`] leased the port from.
+    #[cfg(unix)]
+    Broker,
+}
+
+impl<'a> PortGuard<'a> {
+    pub(crate) fn new(allocator: &'a PortAllocator, port: u16) -> Self {
+        Self {
+            owner: Owner::Allocator(allocator),
+            port,
+        }
+    }
+
+    /// The guarded port.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Keeps the port handed out for the rest of the run and returns it.
+    pub fn into_inner(self) -> u16 {
+        let port = self.port;
+        std::mem::forget(self);
+        port
+    }
+}
+
+#[cfg(unix)]
+impl PortGuard<'static> {
+    pub(crate) fn brokered(port: u16) -> Self {
+        Self {
+            owner: Owner::Broker,
+            port,
+        }
+    }
+}
+
+impl fmt::Display for PortGuard<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.port.fmt(f)
+    }
+}
+
+impl Drop for PortGuard<'_> {
+    fn drop(&mut self) {
+        // Nothing can be done about a failure in `drop`. The port is just not reused then.
+        let _ = match self.owner {
+            Owner::Allocator(allocator) => allocator.release_port(self.port),
+            #[cfg(unix)]
+            Owner::Broker => crate::broker::release_held(self.port),
+        };
+    }
+}
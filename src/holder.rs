@@ -0,0 +1,199 @@
+//! Diagnostics about the processes, which hold ports.
+//!
+//! On Linux, the sockets using a port are looked up in `/proc/net/{tcp,tcp6,udp,udp6}`, and their
+//! inodes are matched against the `socket:[<inode>]` links in `/proc/<pid>/fd`. Sockets of
+//! processes, whose file descriptors can't be read, e.g. of other users, have no known owner.
+
+use std::io;
+
+#[cfg(target_os = "linux")]
+use std::{collections::HashMap, fmt, fs};
+
+#[cfg(target_os = "linux")]
+use crate::Protocol;
+
+/// The `/proc/net` tables together with the protocol of their sockets.
+#[cfg(target_os = "linux")]
+pub(crate) const TABLES: [(&str, Protocol); 4] = [
+    ("tcp", Protocol::Tcp),
+    ("tcp6", Protocol::Tcp),
+    ("udp", Protocol::Udp),
+    ("udp6", Protocol::Udp),
+];
+
+/// Process, which holds a socket on a port. See [`who_holds`].
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PortHolder {
+    /// Protocol of the socket.
+    pub protocol: Protocol,
+    /// Id of the owning process, if it could be found.
+    pub pid: Option<u32>,
+    /// Command line of the owning process, if it could be read.
+    pub command: Option<String>,
+}
+
+#[cfg(target_os = "linux")]
+impl fmt::Display for PortHolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let protocol = match self.protocol {
+            Protocol::Udp => "UDP",
+            _ => "TCP",
+        };
+        match (self.pid, &self.command) {
+            (Some(pid), Some(command)) => write!(f, "pid {} ({}) over {}", pid, command, protocol),
+            (Some(pid), None) => write!(f, "pid {} over {}", pid, protocol),
+            (None, _) => write!(f, "an unknown process over {}", protocol),
+        }
+    }
+}
+
+/// Returns the processes, which have a socket bound to `port` on any address and in any state.
+/// Every process is listed once per protocol.
+///
+/// # Examples
+/// ```
+/// use std::net::TcpListener;
+/// use unique_port::who_holds;
+///
+/// let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+/// let port = listener.local_addr().unwrap().port();
+/// let holders = who_holds(port).unwrap();
+/// assert!(holders.iter().any(|holder| holder.pid == Some(std::process::id())));
+/// ```
+#[cfg(target_os = "linux")]
+pub fn who_holds(port: u16) -> io::Result<Vec<PortHolder>> {
+    let mut inodes = HashMap::new();
+    for (table, protocol) in &TABLES {
+        if let Some(contents) = read_table(table)? {
+            for (_, inode) in sockets(&contents).filter(|(local, _)| *local == port) {
+                inodes.insert(inode, *protocol);
+            }
+        }
+    }
+    let owners = socket_owners(&inodes);
+
+    let mut holders = Vec::new();
+    for (inode, protocol) in inodes {
+        let pid = owners.get(&inode).copied();
+        let holder = PortHolder {
+            protocol,
+            pid,
+            command: pid.and_then(command_line),
+        };
+        if !holders.contains(&holder) {
+            holders.push(holder);
+        }
+    }
+    Ok(holders)
+}
+
+/// Reads a `/proc/net` table. Returns `None`, if the table doesn't exist, e.g. as the kernel is
+/// built without IPv6.
+#[cfg(target_os = "linux")]
+pub(crate) fn read_table(table: &str) -> io::Result<Option<String>> {
+    match fs::read_to_string(format!("/proc/net/{}", table)) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Local ports and inodes of the sockets from a `/proc/net` table. Each row looks like
+/// `0: 0100007F:1F90 00000000:0000 0A ... <inode> ...`, with the port in hex after the colon and
+/// the inode in the tenth column.
+#[cfg(target_os = "linux")]
+pub(crate) fn sockets(table: &str) -> impl Iterator<Item = (u16, u64)> + '_ {
+    table.lines().skip(1).filter_map(|row| {
+        let mut columns = row.split_whitespace();
+        let local = columns.nth(1)?;
+        let port = u16::from_str_radix(local.rsplit(':').next()?, 16).ok()?;
+        let inode = columns.nth(7)?.parse().ok()?;
+        Some((port, inode))
+    })
+}
+
+/// Maps the socket inodes, which are keys of `inodes`, to the ids of the processes owning them.
+/// Processes, which disappear or can't be inspected meanwhile, are skipped.
+#[cfg(target_os = "linux")]
+fn socket_owners<T>(inodes: &HashMap<u64, T>) -> HashMap<u64, u32> {
+    let mut owners = HashMap::new();
+    let processes = match fs::read_dir("/proc") {
+        Ok(processes) => processes,
+        Err(_) => return owners,
+    };
+    for process in processes.flatten() {
+        let pid = match process
+            .file_name()
+            .to_str()
+            .and_then(|pid| pid.parse().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        let fds = match fs::read_dir(process.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        for fd in fds.flatten() {
+            let inode = fs::read_link(fd.path()).ok().and_then(|target| {
+                let target = target.to_str()?;
+                target
+                    .strip_prefix("socket:[")?
+                    .strip_suffix(']')?
+                    .parse()
+                    .ok()
+            });
+            if let Some(inode) = inode.filter(|inode| inodes.contains_key(inode)) {
+                owners.entry(inode).or_insert(pid);
+            }
+        }
+    }
+    owners
+}
+
+/// Command line of the process, with its arguments separated by spaces, or its name for kernel
+/// threads, which have no command line.
+#[cfg(target_os = "linux")]
+fn command_line(pid: u32) -> Option<String> {
+    let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+    let command = cmdline
+        .split(|byte| *byte == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(String::from_utf8_lossy)
+        .collect::<Vec<_>>()
+        .join(" ");
+    if !command.is_empty() {
+        return Some(command);
+    }
+    let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+    Some(comm.trim_end().to_owned()).filter(|comm| !comm.is_empty())
+}
+
+/// Adds the holders of `port` to `error`, if it says that the port is in use.
+#[cfg(target_os = "linux")]
+pub(crate) fn annotate(port: u16, error: io::Error) -> io::Error {
+    if error.kind() != io::ErrorKind::AddrInUse {
+        return error;
+    }
+    match who_holds(port) {
+        Ok(holders) if !holders.is_empty() => {
+            let holders = holders
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            io::Error::new(
+                error.kind(),
+                format!("Port {} is held by {}: {}", port, holders, error),
+            )
+        }
+        _ => error,
+    }
+}
+
+/// Holders of ports can't be found on this platform, so `error` is returned as is.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn annotate(_port: u16, error: io::Error) -> io::Error {
+    error
+}
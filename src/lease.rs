@@ -0,0 +1,76 @@
+//! Time-limited claim over a port, which has to be renewed to be kept.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::{PortAllocator, Result};
+
+/// A unique port, which is handed out for a limited time. Unless the lease is renewed with
+/// [`Lease::renew`] within its TTL, it expires and the allocator reclaims the port on a later
+/// allocation, so a test, which got stuck, doesn't keep its port taken forever. Dropping the lease
+/// gives the port back right away, like [`crate::PortGuard`] does.
+///
+/// Leases are tracked per allocator. To lease ports across processes, see [`crate::broker`].
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use unique_port::PortAllocator;
+///
+/// let allocator = PortAllocator::new(20000..21000);
+/// let lease = allocator.lease_unique_free_port(Duration::from_millis(50)).unwrap();
+/// lease.renew().unwrap();
+///
+/// std::thread::sleep(Duration::from_millis(100));
+/// assert!(lease.renew().is_err());
+/// assert_eq!(lease.port(), allocator.get_unique_free_port().unwrap());
+///
+/// // TTLs beyond what the clock can represent are rejected
+/// assert!(allocator.lease_unique_free_port(Duration::MAX).is_err());
+/// ```
+#[derive(Debug)]
+pub struct Lease<'a> {
+    allocator: &'a PortAllocator,
+    port: u16,
+    id: u64,
+    ttl: Duration,
+}
+
+impl<'a> Lease<'a> {
+    pub(crate) fn new(allocator: &'a PortAllocator, port: u16, id: u64, ttl: Duration) -> Self {
+        Self {
+            allocator,
+            port,
+            id,
+            ttl,
+        }
+    }
+
+    /// The leased port.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Time, for which the lease is extended by every renewal.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Extends the lease by its TTL, counting from now. Fails with
+    /// [`crate::Error::LeaseExpired`], if the lease has already expired.
+    pub fn renew(&self) -> Result<()> {
+        self.allocator.renew_lease(self.port, self.id)
+    }
+}
+
+impl fmt::Display for Lease<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.port.fmt(f)
+    }
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        self.allocator.end_lease(self.port, self.id);
+    }
+}
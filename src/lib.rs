@@ -1,39 +1,136 @@
 #![crate_name = "unique_port"]
 
-use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+use std::net::{IpAddr, TcpListener, UdpSocket};
 use std::ops::Range;
-use std::sync::Mutex;
+use std::time::Duration;
 
-use once_cell::sync::Lazy;
+mod allocator;
+#[cfg(feature = "async-std")]
+pub mod async_std;
+#[cfg(unix)]
+pub mod broker;
+pub mod config;
+mod ephemeral;
+mod error;
+mod guard;
+mod holder;
+mod lease;
+mod mock;
+mod probe;
+mod protocol;
+#[cfg(unix)]
+pub mod registry;
+mod reservation;
+mod scope;
+mod state;
+#[cfg(feature = "tokio")]
+pub mod tokio;
 
-static PORT_IDX: Lazy<Mutex<u16>> = Lazy::new(|| Mutex::new(1000));
+pub use allocator::{AllocatePort, PortAllocator};
+pub use config::{Config, Strategy};
+pub use ephemeral::ephemeral_port_range;
+pub use error::{Error, Result};
+pub use guard::PortGuard;
+#[cfg(target_os = "linux")]
+pub use holder::{who_holds, PortHolder};
+pub use lease::Lease;
+pub use mock::MockAllocator;
+#[cfg(target_os = "linux")]
+pub use probe::ProcNetProbe;
+pub use probe::{BindProbe, ConnectProbe, PortProbe};
+pub use protocol::Protocol;
+pub use reservation::PortReservation;
+pub use scope::{
+    register_scope, scope_collisions, start_port_for, ScopeCollision, ScopedAllocator, SCOPE_WINDOW,
+};
+pub use state::WrapPolicy;
+
+/// Turns a function, which takes ports as arguments, into a test. Each argument gets a unique free
+/// port, which is released when the test ends, even if it panics. A single array argument gets all
+/// its ports at once instead. `ports = N` is only needed, if the length of the array isn't a
+/// literal.
+///
+/// # Examples
+/// ```
+/// #[unique_port::test]
+/// fn peer_ports_differ(p2p: u16, api: u16) {
+///     assert_ne!(p2p, api);
+/// }
+///
+/// const PEERS: usize = 3;
+///
+/// #[unique_port::test(ports = 3)]
+/// fn cluster(ports: [u16; PEERS]) {
+///     assert!(ports.windows(2).all(|pair| pair[0] != pair[1]));
+/// }
+/// ```
+#[cfg(feature = "macros")]
+pub use unique_port_macros::test;
 
 /// Generates a unique offset, from which `get_unique_free_port` will start to find free ports
-/// incrementally. The value is higher than 1000, and less than `u16::MAX - 1000`. It uses the full
-/// module path and the enclosed function name, so it's always the same for the scope of the same
-/// function.
+/// incrementally. The value is at least 1000, and less than `u16::MAX`. It uses the full module
+/// path and the enclosed function name, so it's always the same for the scope of the same
+/// function. See [`start_port_for`] for the exact mapping. Windows overlapping the kernel ephemeral
+/// range are re-seeded, so that the search doesn't skip to the end of that range. If the port
+/// windows of two functions overlap, it is reported by [`scope_collisions`]. The window is
+/// [`SCOPE_WINDOW`] ports long, or as long as the one of [`scoped_allocator`]. See
+/// [`register_scope`] for details.
+///
+/// # Examples
+/// ```
+/// use unique_port::{generate_unique_start_port, get_unique_free_port, set_port_index};
+///
+/// let start = generate_unique_start_port!();
+/// set_port_index(start).unwrap();
+/// // Nothing is skipped, unless the port is taken
+/// assert!(get_unique_free_port().unwrap() < start + 100);
+/// ```
 #[macro_export]
 macro_rules! generate_unique_start_port {
-    () => {{
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
+    () => {
+        $crate::register_scope($crate::__scope_name!())
+    };
+}
+
+/// Creates a [`ScopedAllocator`] for the enclosing function. Its window starts at the port, which
+/// [`generate_unique_start_port`] returns for the function, and is [`SCOPE_WINDOW`] ports long,
+/// unless the length is given.
+///
+/// # Examples
+/// ```
+/// use unique_port::scoped_allocator;
+///
+/// let allocator = scoped_allocator!(50);
+/// let port = allocator.get_unique_free_port().unwrap();
+/// assert!(allocator.window().contains(&port));
+/// ```
+#[macro_export]
+macro_rules! scoped_allocator {
+    () => {
+        $crate::scoped_allocator!($crate::SCOPE_WINDOW)
+    };
+    ($window:expr) => {
+        $crate::ScopedAllocator::new($crate::__scope_name!(), $window)
+    };
+}
 
+/// Full path of the enclosing function.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __scope_name {
+    () => {{
         fn f() {}
         fn type_name_of<T>(_: T) -> &'static str {
             std::any::type_name::<T>()
         }
         let name = type_name_of(f);
-        let name = &name[..name.len() - 3];
-        let mut hasher = DefaultHasher::new();
-        name.hash(&mut hasher);
-        // we have offset of 1000, which is the starting port numnber, so we should move the whole
-        // offset and prevent it from overflowing u16::MAX
-        1000 + (hasher.finish() % ((u16::MAX - 1000) as u64)) as u16
+        &name[..name.len() - 3]
     }};
 }
 
 /// Sets the port number, from which `get_unique_free_port()` will start generating free ports
-/// incrementally.
+/// incrementally. This also forgets, which ports were already handed out, so they may be returned
+/// again.
 ///
 /// # Examples
 ///
@@ -51,17 +148,72 @@ macro_rules! generate_unique_start_port {
 /// assert_eq!(pindex, unique_port::get_unique_free_port().unwrap());
 ///
 /// ```
-pub fn set_port_index(pindex: u16) -> Result<(), String> {
-    *PORT_IDX
-        .lock()
-        .map_err(|_| "Failed to aquire the lock".to_owned())? = pindex;
+pub fn set_port_index(pindex: u16) -> Result<()> {
+    PortAllocator::global()?.set_port_index(pindex)
+}
 
-    Ok(())
+/// Sets the range, in which free ports are searched. It is `1000..u16::MAX` by default. If the
+/// current port index is outside of the new range, the search starts over from its lower bound.
+///
+/// # Examples
+/// ```
+/// use unique_port::{get_unique_free_port, set_port_index, set_port_range};
+///
+/// set_port_index(40001).unwrap();
+/// set_port_range(20000..30000).unwrap();
+/// let port = get_unique_free_port().unwrap();
+/// assert!((20000..30000).contains(&port));
+/// ```
+pub fn set_port_range(range: Range<u16>) -> Result<()> {
+    PortAllocator::global()?.set_port_range(range)
+}
+
+/// Sets whether ports from the kernel ephemeral range (see [`ephemeral_port_range`]) are skipped.
+/// They are skipped by default, as outgoing connections may take them at any moment. Ports listed
+/// in `ip_local_reserved_ports` are never picked by the kernel, so they are handed out anyway.
+///
+/// # Examples
+/// ```
+/// use unique_port::{ephemeral_port_range, get_unique_free_port, set_exclude_ephemeral_ports};
+///
+/// let port = get_unique_free_port().unwrap();
+/// if let Some(range) = ephemeral_port_range() {
+///     assert!(!range.contains(&port));
+/// }
+///
+/// // Restore the behaviour of older versions.
+/// set_exclude_ephemeral_ports(false).unwrap();
+/// ```
+pub fn set_exclude_ephemeral_ports(exclude: bool) -> Result<()> {
+    PortAllocator::global()?.set_exclude_ephemeral_ports(exclude)
+}
+
+/// Sets what happens, when the end of the port range is reached. By default allocation fails with
+/// [`Error::RangeExhausted`].
+///
+/// # Examples
+/// ```
+/// use unique_port::{get_unique_free_port, set_port_index, set_port_range, set_wrap_policy, WrapPolicy};
+///
+/// set_port_range(20000..20100).unwrap();
+/// set_wrap_policy(WrapPolicy::WrapAround).unwrap();
+/// set_port_index(20099).unwrap();
+///
+/// let last = get_unique_free_port().unwrap();
+/// let wrapped = get_unique_free_port().unwrap();
+/// assert!(wrapped < last);
+/// ```
+pub fn set_wrap_policy(policy: WrapPolicy) -> Result<()> {
+    PortAllocator::global()?.set_wrap_policy(policy)
 }
 
 /// Returns a free unique local port. Every time a call to this function during one run should
 /// return a unique address.
 ///
+/// On Unix, if `UNIQUE_PORT_BROKER` is set, the port is leased from the [`broker`] listening on
+/// that socket instead. The lease is renewed in the background until the port is released with
+/// [`release_port`] or the process exits.
+///
 /// # Examples
 /// ```
 /// use unique_port::get_unique_free_port;
@@ -70,21 +222,199 @@ pub fn set_port_index(pindex: u16) -> Result<(), String> {
 /// let port_2 = get_unique_free_port().unwrap();
 /// assert_ne!(port_1, port_2);
 /// ```
-pub fn get_unique_free_port() -> Result<u16, String> {
-    let mut port_idx = PORT_IDX
-        .lock()
-        .map_err(|_| "Failed to aquire the lock".to_owned())?;
-    let result = get_free_port(*port_idx..u16::MAX);
-    if let Ok(port) = result {
-        *port_idx = port + 1;
+pub fn get_unique_free_port() -> Result<u16> {
+    #[cfg(unix)]
+    if let Some(socket) = broker::from_env() {
+        return broker::lease_held(socket);
+    }
+    PortAllocator::global()?.get_unique_free_port()
+}
+
+/// Same as [`get_unique_free_port`], but gives the port back with [`release_port`] when the returned
+/// guard is dropped.
+pub fn get_unique_free_port_guard() -> Result<PortGuard<'static>> {
+    #[cfg(unix)]
+    if let Some(socket) = broker::from_env() {
+        return broker::lease_held(socket).map(PortGuard::brokered);
+    }
+    PortAllocator::global()?.get_unique_free_port_guard()
+}
+
+/// Same as [`get_unique_free_port`], but the port is only handed out for `ttl`, unless the returned
+/// lease is renewed. See [`Lease`]. Fails with [`Error::InvalidArgument`], if `ttl` is too long to
+/// compute the expiration time.
+///
+/// # Examples
+/// ```
+/// use std::time::Duration;
+/// use unique_port::lease_unique_free_port;
+///
+/// let lease = lease_unique_free_port(Duration::from_secs(30)).unwrap();
+/// lease.renew().unwrap();
+/// ```
+pub fn lease_unique_free_port(ttl: Duration) -> Result<Lease<'static>> {
+    local_allocator()?.lease_unique_free_port(ttl)
+}
+
+/// Gives a port, returned by one of the `get_unique_*` functions, back for reuse. Released ports are
+/// handed out again before any new ones. Ports, which aren't handed out, are ignored.
+///
+/// On Unix, if `UNIQUE_PORT_BROKER` is set, the port is given back to the [`broker`] instead.
+///
+/// # Examples
+/// ```
+/// use unique_port::{get_unique_free_port, release_port};
+///
+/// let port = get_unique_free_port().unwrap();
+/// release_port(port).unwrap();
+/// assert_eq!(port, get_unique_free_port().unwrap());
+/// ```
+pub fn release_port(port: u16) -> Result<()> {
+    #[cfg(unix)]
+    if broker::from_env().is_some() {
+        return broker::release_held(port);
     }
-    result
+    PortAllocator::global()?.release_port(port)
+}
+
+/// Returns a free unique local UDP port. Shares uniqueness with [`get_unique_free_port`], so the
+/// two never return the same port during one run.
+///
+/// # Examples
+/// ```
+/// use std::net::UdpSocket;
+/// use unique_port::get_unique_free_udp_port;
+///
+/// let port = get_unique_free_udp_port().unwrap();
+/// assert!(UdpSocket::bind(("127.0.0.1", port)).is_ok());
+/// ```
+pub fn get_unique_free_udp_port() -> Result<u16> {
+    local_allocator()?.get_unique_free_udp_port()
+}
+
+/// Returns a free unique local port, which is free for the given protocol.
+///
+/// # Examples
+/// ```
+/// use std::net::{TcpListener, UdpSocket};
+/// use unique_port::{get_unique_free_port_for, Protocol};
+///
+/// let port = get_unique_free_port_for(Protocol::Both).unwrap();
+/// let _tcp = TcpListener::bind(("127.0.0.1", port)).unwrap();
+/// let _udp = UdpSocket::bind(("127.0.0.1", port)).unwrap();
+/// ```
+pub fn get_unique_free_port_for(protocol: Protocol) -> Result<u16> {
+    local_allocator()?.get_unique_free_port_for(protocol)
+}
+
+/// Returns a free unique port, which is free for the given protocol on every address from `addrs`.
+///
+/// # Examples
+/// ```
+/// use std::net::{Ipv4Addr, TcpListener};
+/// use unique_port::{get_unique_free_port_on, Protocol};
+///
+/// let addrs = [Ipv4Addr::LOCALHOST.into(), Ipv4Addr::UNSPECIFIED.into()];
+/// let port = get_unique_free_port_on(&addrs, Protocol::Tcp).unwrap();
+/// assert!(TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).is_ok());
+/// ```
+pub fn get_unique_free_port_on(addrs: &[IpAddr], protocol: Protocol) -> Result<u16> {
+    local_allocator()?.get_unique_free_port_on(addrs, protocol)
 }
 
-/// Returns empty port from range. Can be not unique
-fn get_free_port(ports: Range<u16>) -> Result<u16, String> {
-    ports
-        .into_iter()
-        .find(|port| TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, *port)).is_ok())
-        .ok_or_else(|| "Failed to get empty port".to_owned())
+/// Returns `len` consecutive free unique local ports. All of them are handed out at once, so no
+/// other allocation can get a port from the middle of the block. Fails with
+/// [`Error::InvalidArgument`], if `len` is zero.
+///
+/// # Examples
+/// ```
+/// use unique_port::{get_unique_free_port, get_unique_free_port_block};
+///
+/// let block = get_unique_free_port_block(3).unwrap();
+/// assert_eq!(block.len(), 3);
+/// assert!(!block.contains(&get_unique_free_port().unwrap()));
+/// assert!(get_unique_free_port_block(0).is_err());
+/// ```
+pub fn get_unique_free_port_block(len: u16) -> Result<Range<u16>> {
+    local_allocator()?.get_unique_free_port_block(len)
+}
+
+/// Same as [`get_unique_free_port`], but keeps the port bound until the returned reservation is
+/// released or dropped.
+///
+/// # Examples
+/// ```
+/// use unique_port::reserve_unique_free_port;
+///
+/// let reservation = reserve_unique_free_port().unwrap();
+/// let port = reservation.release();
+/// // Bind the port right away here.
+/// ```
+pub fn reserve_unique_free_port() -> Result<PortReservation> {
+    local_allocator()?.reserve_unique_free_port()
+}
+
+/// Returns a listener bound to a free unique local port. Unlike [`get_unique_free_port`], the
+/// port is never given up between probing and use.
+///
+/// # Examples
+/// ```
+/// use unique_port::get_unique_free_listener;
+///
+/// let listener = get_unique_free_listener().unwrap();
+/// let port = listener.local_addr().unwrap().port();
+/// assert_ne!(port, get_unique_free_listener().unwrap().local_addr().unwrap().port());
+/// ```
+pub fn get_unique_free_listener() -> Result<TcpListener> {
+    local_allocator()?.get_unique_free_listener()
+}
+
+/// Returns a UDP socket bound to a free unique local port.
+///
+/// # Examples
+/// ```
+/// use unique_port::get_unique_free_udp_socket;
+///
+/// let socket = get_unique_free_udp_socket().unwrap();
+/// assert!(socket.local_addr().unwrap().ip().is_loopback());
+/// ```
+pub fn get_unique_free_udp_socket() -> Result<UdpSocket> {
+    local_allocator()?.get_unique_free_udp_socket()
+}
+
+/// Returns a free local port, which is unique across all processes on the machine using the same
+/// [`registry::Registry`]. The port is leased to the current process until it exits, after which
+/// any other process may reclaim it.
+///
+/// # Examples
+/// ```
+/// use unique_port::get_globally_unique_free_port;
+///
+/// let port_1 = get_globally_unique_free_port().unwrap();
+/// let port_2 = get_globally_unique_free_port().unwrap();
+/// assert_ne!(port_1, port_2);
+/// ```
+#[cfg(unix)]
+pub fn get_globally_unique_free_port() -> Result<u16> {
+    get_unique_free_port_in(&registry::Registry::default())
+}
+
+/// Same as [`get_globally_unique_free_port`], but coordinates through the given registry.
+#[cfg(unix)]
+pub fn get_unique_free_port_in(registry: &registry::Registry) -> Result<u16> {
+    local_allocator()?.get_unique_free_port_in(registry)
+}
+
+/// Returns the global allocator for the functions, which the broker can't serve. The [`broker`]
+/// only hands out single TCP ports, so these fail with [`Error::Broker`], if `UNIQUE_PORT_BROKER`
+/// is set, instead of handing out ports, which the broker may have leased to another process.
+fn local_allocator() -> Result<&'static PortAllocator> {
+    #[cfg(unix)]
+    if broker::from_env().is_some() {
+        return Err(Error::Broker(format!(
+            "Only `get_unique_free_port` and `get_unique_free_port_guard` work with `{}`",
+            broker::BROKER_ENV
+        )));
+    }
+    PortAllocator::global()
 }
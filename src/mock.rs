@@ -0,0 +1,134 @@
+//! In-memory allocator for hermetic tests of code, which depends on port allocation.
+
+use std::collections::{BTreeSet, VecDeque};
+use std::ops::Range;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::{AllocatePort, Error, PortAllocator, Result};
+
+/// Allocator, which never touches the network stack.
+///
+/// It either returns a scripted sequence of ports, or simulates a range, in which some ports are
+/// occupied.
+///
+/// # Examples
+/// ```
+/// use unique_port::{AllocatePort, MockAllocator};
+///
+/// fn peer_ports(allocator: &impl AllocatePort) -> (u16, u16) {
+///     (
+///         allocator.get_unique_free_port().unwrap(),
+///         allocator.get_unique_free_port().unwrap(),
+///     )
+/// }
+///
+/// let scripted = MockAllocator::scripted(vec![1337, 8080]);
+/// assert_eq!((1337, 8080), peer_ports(&scripted));
+/// assert!(matches!(
+///     scripted.get_unique_free_port(),
+///     Err(unique_port::Error::ScriptExhausted)
+/// ));
+///
+/// let simulated = MockAllocator::simulated(1000..2000, vec![1001]);
+/// assert_eq!((1000, 1002), peer_ports(&simulated));
+/// simulated.occupy(1003);
+/// assert_eq!(1004, simulated.get_unique_free_port().unwrap());
+/// assert_eq!(vec![1000, 1002, 1004], simulated.handed_out());
+/// ```
+#[derive(Debug)]
+pub struct MockAllocator {
+    mode: Mode,
+    handed_out: Mutex<Vec<u16>>,
+}
+
+#[derive(Debug)]
+enum Mode {
+    Scripted(Mutex<VecDeque<u16>>),
+    Simulated {
+        allocator: PortAllocator,
+        occupied: Arc<Mutex<BTreeSet<u16>>>,
+    },
+}
+
+impl MockAllocator {
+    /// Returns `ports` in order and fails with [`Error::ScriptExhausted`] after that.
+    pub fn scripted(ports: impl IntoIterator<Item = u16>) -> Self {
+        Self::with_mode(Mode::Scripted(Mutex::new(ports.into_iter().collect())))
+    }
+
+    /// Hands out unique ports from `range` like [`PortAllocator`] does, treating the `occupied`
+    /// ports as taken.
+    pub fn simulated(range: Range<u16>, occupied: impl IntoIterator<Item = u16>) -> Self {
+        let occupied = Arc::new(Mutex::new(occupied.into_iter().collect::<BTreeSet<_>>()));
+        let allocator = PortAllocator::new(range);
+        let probed = Arc::clone(&occupied);
+        allocator
+            .set_probe(move |port| {
+                !probed
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .contains(&port)
+            })
+            .expect("Allocator isn't shared yet");
+        allocator
+            .set_exclude_ephemeral_ports(false)
+            .expect("Allocator isn't shared yet");
+        Self::with_mode(Mode::Simulated {
+            allocator,
+            occupied,
+        })
+    }
+
+    fn with_mode(mode: Mode) -> Self {
+        Self {
+            mode,
+            handed_out: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Marks `port` as taken by someone else. Does nothing for scripted allocators.
+    pub fn occupy(&self, port: u16) {
+        if let Mode::Simulated { occupied, .. } = &self.mode {
+            occupied
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(port);
+        }
+    }
+
+    /// Marks `port` as free again. Does nothing for scripted allocators.
+    pub fn vacate(&self, port: u16) {
+        if let Mode::Simulated { occupied, .. } = &self.mode {
+            occupied
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(&port);
+        }
+    }
+
+    /// All ports handed out so far, in order.
+    pub fn handed_out(&self) -> Vec<u16> {
+        self.handed_out
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl AllocatePort for MockAllocator {
+    fn get_unique_free_port(&self) -> Result<u16> {
+        let port = match &self.mode {
+            Mode::Scripted(ports) => ports
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .pop_front()
+                .ok_or(Error::ScriptExhausted)?,
+            Mode::Simulated { allocator, .. } => allocator.get_unique_free_port()?,
+        };
+        self.handed_out
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(port);
+        Ok(port)
+    }
+}
@@ -0,0 +1,157 @@
+//! Strategies for checking whether a port is free.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use crate::Protocol;
+
+/// Checks whether a port is free. Allocators hand out only ports, which pass their probe.
+///
+/// Closures `Fn(u16) -> bool` are probes too, which is handy for deterministic tests. Ports, which
+/// they reject, fail with [`io::ErrorKind::Other`], as they aren't necessarily in use.
+///
+/// # Examples
+/// ```
+/// use std::io;
+/// use unique_port::{Error, PortAllocator};
+///
+/// let allocator = PortAllocator::new(20000..20020);
+/// allocator.set_probe(|port: u16| port % 10 == 0).unwrap();
+/// assert_eq!(20000, allocator.get_unique_free_port().unwrap());
+/// assert_eq!(20010, allocator.get_unique_free_port().unwrap());
+///
+/// match allocator.get_unique_free_port() {
+///     Err(Error::RangeExhausted {
+///         last_error: Some(error),
+///         ..
+///     }) => assert_eq!(io::ErrorKind::Other, error.kind()),
+///     other => panic!("unexpected {:?}", other),
+/// }
+/// ```
+pub trait PortProbe: Send + Sync {
+    /// Returns `Ok(())` if `port` is free, or the reason why it isn't.
+    fn probe(&self, port: u16) -> io::Result<()>;
+}
+
+impl<F> PortProbe for F
+where
+    F: Fn(u16) -> bool + Send + Sync,
+{
+    fn probe(&self, port: u16) -> io::Result<()> {
+        if self(port) {
+            Ok(())
+        } else {
+            // Not `AddrInUse`, so that exhaustion doesn't go looking for holders of the port
+            Err(io::Error::other("Port is rejected by the probe"))
+        }
+    }
+}
+
+/// Tries to bind the port with a protocol on each of the addresses. This is the default probe.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BindProbe {
+    addrs: Vec<IpAddr>,
+    protocol: Protocol,
+}
+
+impl Default for BindProbe {
+    /// Probes TCP on `127.0.0.1`.
+    fn default() -> Self {
+        Self::new(vec![Ipv4Addr::LOCALHOST.into()], Protocol::Tcp)
+    }
+}
+
+impl BindProbe {
+    /// Probes `protocol` on every address from `addrs`.
+    pub fn new(addrs: Vec<IpAddr>, protocol: Protocol) -> Self {
+        Self { addrs, protocol }
+    }
+}
+
+impl PortProbe for BindProbe {
+    fn probe(&self, port: u16) -> io::Result<()> {
+        self.addrs
+            .iter()
+            .try_for_each(|ip| self.protocol.probe(SocketAddr::new(*ip, port)))
+    }
+}
+
+/// Considers a TCP port free if connecting to it on `127.0.0.1` is refused. Unlike binding, this
+/// doesn't need the permission to bind the port, but it only notices listening sockets.
+///
+/// # Examples
+/// ```
+/// use std::net::TcpListener;
+/// use unique_port::{ConnectProbe, PortProbe};
+///
+/// let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+/// let port = listener.local_addr().unwrap().port();
+/// assert!(ConnectProbe::default().probe(port).is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectProbe {
+    timeout: Duration,
+}
+
+impl Default for ConnectProbe {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(100))
+    }
+}
+
+impl ConnectProbe {
+    /// Gives up connecting after `timeout`, treating the port as taken.
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+}
+
+impl PortProbe for ConnectProbe {
+    fn probe(&self, port: u16) -> io::Result<()> {
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+        match TcpStream::connect_timeout(&addr, self.timeout) {
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "Port accepts connections",
+            )),
+            Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+/// Considers a port free if no socket in `/proc/net/{tcp,tcp6,udp,udp6}` uses it as a local port,
+/// on any address and in any state. Doesn't open any sockets.
+///
+/// # Examples
+/// ```
+/// use std::net::UdpSocket;
+/// use unique_port::{PortProbe, ProcNetProbe};
+///
+/// let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+/// let port = socket.local_addr().unwrap().port();
+/// assert!(ProcNetProbe.probe(port).is_err());
+/// ```
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProcNetProbe;
+
+#[cfg(target_os = "linux")]
+impl PortProbe for ProcNetProbe {
+    fn probe(&self, port: u16) -> io::Result<()> {
+        for (table, _) in &crate::holder::TABLES {
+            let contents = match crate::holder::read_table(table)? {
+                Some(contents) => contents,
+                None => continue,
+            };
+            if crate::holder::sockets(&contents).any(|(used, _)| used == port) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("Port is listed in /proc/net/{}", table),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
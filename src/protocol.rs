@@ -0,0 +1,30 @@
+//! Transport protocols, for which ports are probed.
+
+use std::io;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+
+/// Protocol, for which a port has to be free.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    /// The port can be bound by a `TcpListener`.
+    #[default]
+    Tcp,
+    /// The port can be bound by a `UdpSocket`.
+    Udp,
+    /// The port can be bound by a `TcpListener` and a `UdpSocket` at the same time.
+    Both,
+}
+
+impl Protocol {
+    /// Checks that `addr` can be bound with this protocol. The sockets are closed right away.
+    pub fn probe(self, addr: SocketAddr) -> io::Result<()> {
+        match self {
+            Self::Tcp => TcpListener::bind(addr).map(drop),
+            Self::Udp => UdpSocket::bind(addr).map(drop),
+            Self::Both => {
+                let _tcp = TcpListener::bind(addr)?;
+                UdpSocket::bind(addr).map(drop)
+            }
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! On-disk lease registry, which lets several processes on the same machine coordinate the ports
+//! they hand out.
+//!
+//! Every lease is a `<port> <pid>` line in a shared file. The file is locked with `flock` for the
+//! whole read-modify-write cycle, and leases of processes that are not alive anymore are dropped on
+//! every access, so a crashed test binary doesn't keep its ports forever.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use crate::Result;
+
+/// Name of the registry file, which is created inside [`std::env::temp_dir`] by default.
+pub const DEFAULT_FILE_NAME: &str = "unique_port.leases";
+
+/// Handle to a lease registry file.
+///
+/// # Examples
+/// ```
+/// use unique_port::registry::Registry;
+///
+/// let path = std::env::temp_dir().join(format!("unique_port_doc_{}", std::process::id()));
+/// // A lease of a process, which doesn't exist anymore
+/// std::fs::write(&path, format!("20000 {}\n", i32::MAX)).unwrap();
+///
+/// let registry = Registry::at(&path);
+/// assert!(registry.leases().unwrap().is_empty());
+/// assert!(registry.lease(20000).unwrap());
+/// assert_eq!(Some(&std::process::id()), registry.leases().unwrap().get(&20000));
+///
+/// registry.release(20000).unwrap();
+/// assert!(registry.leases().unwrap().is_empty());
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registry {
+    path: PathBuf,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::at(std::env::temp_dir().join(DEFAULT_FILE_NAME))
+    }
+}
+
+impl Registry {
+    /// Uses the registry stored at `path`. The file is created on first access.
+    pub fn at(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Path of the registry file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns all live leases as a map from port to the owning process id.
+    pub fn leases(&self) -> Result<BTreeMap<u16, u32>> {
+        self.with_leases(|leases| leases.clone())
+    }
+
+    /// Marks `port` as leased by the current process. Returns `false` if it is already leased by
+    /// another live process.
+    pub fn lease(&self, port: u16) -> Result<bool> {
+        self.with_leases(|leases| {
+            let pid = std::process::id();
+            match leases.get(&port) {
+                Some(owner) if *owner != pid => false,
+                _ => {
+                    leases.insert(port, pid);
+                    true
+                }
+            }
+        })
+    }
+
+    /// Removes the lease of `port`, if it belongs to the current process.
+    pub fn release(&self, port: u16) -> Result<()> {
+        self.with_leases(|leases| {
+            if leases.get(&port) == Some(&std::process::id()) {
+                leases.remove(&port);
+            }
+        })
+    }
+
+    /// Locks the registry, reclaims stale leases and runs `f` over the rest. Whatever `f` leaves
+    /// in the map is written back before the lock is released.
+    pub(crate) fn with_leases<T>(&self, f: impl FnOnce(&mut BTreeMap<u16, u32>) -> T) -> Result<T> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)?;
+        let _lock = FileLock::acquire(&file)?;
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let mut leases = parse(&contents);
+        leases.retain(|_, pid| is_alive(*pid));
+
+        let result = f(&mut leases);
+
+        let mut contents = String::new();
+        for (port, pid) in &leases {
+            contents.push_str(&format!("{} {}\n", port, pid));
+        }
+        file.seek(SeekFrom::Start(0))?;
+        file.set_len(0)?;
+        file.write_all(contents.as_bytes())?;
+
+        Ok(result)
+    }
+}
+
+/// Malformed lines are skipped, as they can only come from an interrupted write.
+fn parse(contents: &str) -> BTreeMap<u16, u32> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let port = fields.next()?.parse().ok()?;
+            let pid = fields.next()?.parse().ok()?;
+            Some((port, pid))
+        })
+        .collect()
+}
+
+fn is_alive(pid: u32) -> bool {
+    if pid == std::process::id() {
+        return true;
+    }
+    // Signal 0 only checks that the process exists. `EPERM` means it exists, but belongs to
+    // another user.
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Exclusive `flock`, which is released on drop. Must not outlive the locked file.
+struct FileLock(RawFd);
+
+impl FileLock {
+    fn acquire(file: &File) -> Result<Self> {
+        let fd = file.as_raw_fd();
+        if unsafe { libc::flock(fd, libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(Self(fd))
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        unsafe {
+            libc::flock(self.0, libc::LOCK_UN);
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Guard, which keeps a found port bound until the caller is ready to use it.
+
+use std::net::{SocketAddr, TcpListener};
+
+use crate::Result;
+
+/// A free port, which is kept bound by this process until [`PortReservation::release`] is called
+/// or the reservation is dropped. This shrinks the window, in which another process can take the
+/// port, to the moment between the release and the caller's own `bind`.
+///
+/// # Examples
+/// ```
+/// use std::net::TcpListener;
+/// use unique_port::reserve_unique_free_port;
+///
+/// let reservation = reserve_unique_free_port().unwrap();
+/// let addr = reservation.addr();
+/// // The port is still taken by the reservation.
+/// assert!(TcpListener::bind(addr).is_err());
+///
+/// reservation.release();
+/// assert!(TcpListener::bind(addr).is_ok());
+/// ```
+#[derive(Debug)]
+pub struct PortReservation {
+    listener: TcpListener,
+    addr: SocketAddr,
+}
+
+impl PortReservation {
+    pub(crate) fn new(listener: TcpListener) -> Result<Self> {
+        let addr = listener.local_addr()?;
+        Ok(Self { listener, addr })
+    }
+
+    /// The reserved port.
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// The address the port is reserved on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Hands the bound listener over to the caller, so the port is never unbound in between.
+    pub fn into_listener(self) -> TcpListener {
+        self.listener
+    }
+
+    /// Unbinds the port, so that the caller can bind it, and returns its number.
+    pub fn release(self) -> u16 {
+        let port = self.port();
+        drop(self.listener);
+        port
+    }
+}
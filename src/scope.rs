@@ -0,0 +1,277 @@
+//! Start ports of test scopes, derived from their names.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::{Deref, Range};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use once_cell::sync::Lazy;
+
+use crate::PortAllocator;
+
+/// Number of ports a scope is expected to use, starting from its start port. Scopes, whose windows
+/// overlap, would hand out the same ports.
+pub const SCOPE_WINDOW: u16 = 100;
+
+/// How many times a scope is re-seeded to move its window out of the ephemeral range, before it is
+/// left there.
+const MAX_RESEEDS: u32 = 16;
+
+static SCOPES: Lazy<Mutex<Scopes>> = Lazy::new(Mutex::default);
+
+struct Scopes {
+    windows: BTreeMap<String, Range<u16>>,
+    collisions: Vec<ScopeCollision>,
+    ephemeral: Option<Range<u16>>,
+}
+
+impl Default for Scopes {
+    fn default() -> Self {
+        Self {
+            windows: BTreeMap::new(),
+            collisions: Vec::new(),
+            ephemeral: crate::ephemeral_port_range(),
+        }
+    }
+}
+
+impl Scopes {
+    /// Records a collision of the scope `name` with every other registered scope, whose window
+    /// overlaps `window`.
+    fn record_collisions(&mut self, name: &str, window: &Range<u16>) {
+        let collisions = self
+            .windows
+            .iter()
+            .filter(|(other, other_window)| *other != name && overlaps(window, other_window))
+            .map(|(other, other_window)| ScopeCollision {
+                scope: name.to_owned(),
+                window: window.clone(),
+                other: other.clone(),
+                other_window: other_window.clone(),
+            })
+            .collect::<Vec<_>>();
+        self.collisions.extend(collisions);
+    }
+
+    /// Whether `window` overlaps the kernel ephemeral range, from which no port is handed out by
+    /// default.
+    fn is_ephemeral(&self, window: &Range<u16>) -> bool {
+        self.ephemeral
+            .as_ref()
+            .is_some_and(|ephemeral| overlaps(ephemeral, window))
+    }
+
+    /// Registers the scope `name` with a window of `len` ports. See [`register_scope`].
+    fn register(&mut self, name: &str, len: u16) -> Range<u16> {
+        if let Some(registered) = self.windows.get(name).cloned() {
+            // The scope keeps its start, but later scopes have to avoid the longest window
+            let requested = window(registered.start, len);
+            if requested.end > registered.end {
+                self.record_collisions(name, &requested);
+                self.windows.insert(name.to_owned(), requested.clone());
+            }
+            return requested;
+        }
+
+        let mut candidate = window(start_port_for(name), len);
+        let mut attempt = 0;
+        while self.is_ephemeral(&candidate) && attempt < MAX_RESEEDS {
+            attempt += 1;
+            candidate = window(start_port_for(&format!("{}#{}", name, attempt)), len);
+        }
+        // Collisions don't move the window, as that would depend on the order of registration
+        self.record_collisions(name, &candidate);
+        self.windows.insert(name.to_owned(), candidate.clone());
+        candidate
+    }
+}
+
+/// Two scopes, whose port windows overlapped. See [`register_scope`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ScopeCollision {
+    /// The scope, which was registered last.
+    pub scope: String,
+    /// The port window of `scope`.
+    pub window: Range<u16>,
+    /// The already registered scope.
+    pub other: String,
+    /// The port window of `other`.
+    pub other_window: Range<u16>,
+}
+
+impl fmt::Display for ScopeCollision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Port window {}..{} of `{}` overlaps window {}..{} of `{}`",
+            self.window.start,
+            self.window.end,
+            self.scope,
+            self.other_window.start,
+            self.other_window.end,
+            self.other
+        )
+    }
+}
+
+/// Returns the start port of the scope `name`, remembering it for the rest of the process. This is
+/// what [`crate::generate_unique_start_port`] uses.
+///
+/// Usually it is [`start_port_for`]`(name)`. But if the window of [`SCOPE_WINDOW`] ports from it
+/// overlaps the kernel ephemeral range (see [`crate::ephemeral_port_range`]), the scope is
+/// re-seeded with a different start port, as no port would be handed out from the window by
+/// default. The start port thus only depends on the name and the ephemeral range, but not on the
+/// order, in which tests register. If the window overlaps the window of another registered scope,
+/// the collision is recorded (see [`scope_collisions`]), but the start port is kept.
+///
+/// # Examples
+/// ```
+/// use unique_port::{ephemeral_port_range, register_scope};
+///
+/// let start = register_scope("my_crate::tests::connects");
+/// assert_eq!(start, register_scope("my_crate::tests::connects"));
+///
+/// // Hashes to 40767, which is ephemeral on stock Linux
+/// let start = register_scope("my_crate::tests::listens_1");
+/// if let Some(ephemeral) = ephemeral_port_range() {
+///     assert!(start + 100 <= ephemeral.start || start >= ephemeral.end);
+/// }
+/// ```
+pub fn register_scope(name: &str) -> u16 {
+    scopes().register(name, SCOPE_WINDOW).start
+}
+
+fn scopes() -> MutexGuard<'static, Scopes> {
+    SCOPES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Returns all collisions between scope windows, found by [`register_scope`] so far.
+///
+/// # Examples
+/// ```
+/// use unique_port::{register_scope, scope_collisions, start_port_for};
+///
+/// // These names hash to 4019 and 3930
+/// let alpha = register_scope("tests::alpha");
+/// let beta = register_scope("tests::beta_129");
+/// assert_eq!(beta, start_port_for("tests::beta_129"));
+///
+/// let collisions = scope_collisions();
+/// assert_eq!(collisions[0].scope, "tests::beta_129");
+/// assert_eq!(collisions[0].other, "tests::alpha");
+/// assert_eq!(collisions[0].window, 3930..4030);
+/// ```
+pub fn scope_collisions() -> Vec<ScopeCollision> {
+    scopes().collisions.clone()
+}
+
+fn window(start: u16, len: u16) -> Range<u16> {
+    start..start.saturating_add(len)
+}
+
+fn overlaps(a: &Range<u16>, b: &Range<u16>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// Allocator with a private cursor, which hands out ports only from the window of one scope. Tests
+/// running in parallel don't disturb each other's cursor this way. Create it with
+/// [`crate::scoped_allocator`].
+///
+/// All methods of [`PortAllocator`] are available through `Deref`.
+#[derive(Debug)]
+pub struct ScopedAllocator {
+    name: String,
+    window: Range<u16>,
+    allocator: PortAllocator,
+}
+
+impl ScopedAllocator {
+    /// Creates an allocator over `len` ports, starting from [`register_scope`]`(name)`. Overlaps
+    /// with other scopes are checked for the whole window of `len` ports, also if the scope was
+    /// registered before with a shorter window.
+    ///
+    /// The window is kept out of the ephemeral range on registration, where possible. As it is
+    /// chosen explicitly, ephemeral ports in it are not excluded otherwise.
+    ///
+    /// # Examples
+    /// ```
+    /// use unique_port::{scope_collisions, start_port_for, ScopedAllocator};
+    ///
+    /// // These names hash to 4019 and 3226, which is more than `SCOPE_WINDOW` apart
+    /// let alpha = ScopedAllocator::new("tests::alpha", 1000);
+    /// let gamma = ScopedAllocator::new("tests::gamma_14", 1000);
+    /// assert_eq!(gamma.window().start, start_port_for("tests::gamma_14"));
+    ///
+    /// let collisions = scope_collisions();
+    /// assert_eq!(collisions[0].window, gamma.window());
+    /// assert_eq!(collisions[0].other_window, alpha.window());
+    ///
+    /// // Hashes to 40767, which is ephemeral on stock Linux
+    /// let listens = ScopedAllocator::new("my_crate::tests::listens_1", 50);
+    /// assert!(listens.window().contains(&listens.get_unique_free_port().unwrap()));
+    ///
+    /// // These names hash to 7001 and 7201, so only the longer window of the first one overlaps
+    /// let _delta = ScopedAllocator::new("tests::delta_3523", 100);
+    /// let _epsilon = ScopedAllocator::new("tests::delta_11564", 100);
+    /// assert_eq!(1, scope_collisions().len());
+    /// let epsilon = ScopedAllocator::new("tests::delta_11564", 1000);
+    /// assert_eq!(scope_collisions()[1].window, epsilon.window());
+    /// ```
+    pub fn new(name: &str, len: u16) -> Self {
+        let window = scopes().register(name, len);
+        let allocator = PortAllocator::new(window.clone());
+        // Can't fail
+        let _ = allocator.set_exclude_ephemeral_ports(false);
+        Self {
+            name: name.to_owned(),
+            allocator,
+            window,
+        }
+    }
+
+    /// Name of the scope.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Ports, from which this allocator hands out.
+    pub fn window(&self) -> Range<u16> {
+        self.window.clone()
+    }
+}
+
+impl Deref for ScopedAllocator {
+    type Target = PortAllocator;
+
+    fn deref(&self) -> &PortAllocator {
+        &self.allocator
+    }
+}
+
+/// Returns the port, from which allocation starts for the scope `name`. This is the mapping used by
+/// [`crate::generate_unique_start_port`]. The value is at least 1000 and less than `u16::MAX`.
+///
+/// The hash is 64-bit FNV-1a, which is fully specified, so the mapping never changes between
+/// toolchains or platforms.
+///
+/// # Examples
+/// ```
+/// use unique_port::start_port_for;
+///
+/// // The same on every toolchain and platform
+/// assert_eq!(61204, start_port_for("my_crate::tests::connects"));
+/// ```
+pub fn start_port_for(name: &str) -> u16 {
+    // we have offset of 1000, which is the starting port number, so we should move the whole
+    // offset and prevent it from overflowing u16::MAX
+    1000 + (fnv1a(name.as_bytes()) % u64::from(u16::MAX - 1000)) as u16
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+    })
+}
@@ -0,0 +1,410 @@
+//! Allocation state, which is shared by all the free functions of the crate.
+//!
+//! The state is lock-free on the hot path. Threads claim candidate ports by advancing an atomic
+//! cursor and setting a bit in an atomic set of handed out ports, and probe them without holding
+//! any lock. Only the free list of released ports and the probe itself sit behind locks, which are
+//! held just long enough to pop a port or clone a pointer.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::{BindProbe, Config, Error, PortProbe, Result};
+
+/// What to do, when the cursor reaches the upper bound of the allocation range.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WrapPolicy {
+    /// Fail with [`Error::RangeExhausted`].
+    #[default]
+    Fail,
+    /// Restart from the lower bound of the range, skipping the ports, which were already handed
+    /// out.
+    WrapAround,
+}
+
+/// Bit set of all `u16` ports.
+pub(crate) struct PortSet(Box<[u64; 1 << 10]>);
+
+impl PortSet {
+    pub(crate) fn new() -> Self {
+        Self(Box::new([0; 1 << 10]))
+    }
+
+    pub(crate) fn contains(&self, port: u16) -> bool {
+        self.0[usize::from(port >> 6)] & (1 << (port & 63)) != 0
+    }
+
+    pub(crate) fn insert(&mut self, port: u16) {
+        self.0[usize::from(port >> 6)] |= 1 << (port & 63);
+    }
+
+    pub(crate) fn remove(&mut self, port: u16) {
+        self.0[usize::from(port >> 6)] &= !(1 << (port & 63));
+    }
+}
+
+/// Bit set of all `u16` ports, which can be changed concurrently.
+struct AtomicPortSet(Box<[AtomicU64]>);
+
+impl AtomicPortSet {
+    fn new() -> Self {
+        Self((0..1 << 10).map(|_| AtomicU64::new(0)).collect())
+    }
+
+    /// Returns `false` if the port was already in the set.
+    fn insert(&self, port: u16) -> bool {
+        let bit = 1 << (port & 63);
+        self.0[usize::from(port >> 6)].fetch_or(bit, Ordering::AcqRel) & bit == 0
+    }
+
+    /// Returns `false` if the port wasn't in the set.
+    fn remove(&self, port: u16) -> bool {
+        let bit = 1 << (port & 63);
+        self.0[usize::from(port >> 6)].fetch_and(!bit, Ordering::AcqRel) & bit != 0
+    }
+
+    fn clear(&self) {
+        self.0
+            .iter()
+            .for_each(|word| word.store(0, Ordering::Release));
+    }
+}
+
+/// Claim over a port, which is being probed. Dropping it gives the port up.
+struct Claim<'a> {
+    state: &'a State,
+    port: u16,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.state.issued.remove(self.port);
+        // The cursor has already moved past the port, so if the probe panicked, the port would
+        // be lost, although nothing is known to be wrong with it.
+        if std::thread::panicking() {
+            self.state.released().push_front(self.port);
+        }
+    }
+}
+
+/// Time-limited claim over a handed out port. See [`crate::Lease`].
+struct LeaseEntry {
+    /// Tells a lease apart from a later one of the same port, which was reclaimed in between.
+    id: u64,
+    ttl: Duration,
+    expires: Instant,
+}
+
+pub(crate) struct State {
+    /// Position of the next candidate. It only grows, and is mapped into the range by
+    /// [`State::port_at`], which makes wrapping around a matter of taking the remainder.
+    cursor: AtomicU32,
+    /// Start and end of the range, packed together to be changed at once.
+    range: AtomicU32,
+    wrap: AtomicBool,
+    issued: AtomicPortSet,
+    released: Mutex<VecDeque<u16>>,
+    ephemeral: PortSet,
+    exclude_ephemeral: AtomicBool,
+    probe: RwLock<Arc<dyn PortProbe>>,
+    leases: Mutex<HashMap<u16, LeaseEntry>>,
+    next_lease_id: AtomicU64,
+}
+
+impl State {
+    pub(crate) fn new() -> Self {
+        let state = Self {
+            cursor: AtomicU32::new(0),
+            range: AtomicU32::new(0),
+            wrap: AtomicBool::new(false),
+            issued: AtomicPortSet::new(),
+            released: Mutex::new(VecDeque::new()),
+            ephemeral: crate::ephemeral::ephemeral_ports(),
+            exclude_ephemeral: AtomicBool::new(true),
+            probe: RwLock::new(Arc::new(BindProbe::default())),
+            leases: Mutex::new(HashMap::new()),
+            next_lease_id: AtomicU64::new(0),
+        };
+        state.set_range(1000..u16::MAX);
+        state.set_cursor(1000);
+        state
+    }
+
+    /// Applies the settings, which are set in `config`.
+    pub(crate) fn configure(&self, config: &Config) {
+        if let Some(range) = &config.range {
+            self.set_range(range.clone());
+            self.set_cursor(range.start);
+        }
+        if let Some(base) = config.base {
+            if config.range.is_none() {
+                self.set_range(base..self.range().end);
+            }
+            self.set_cursor(base);
+        }
+        if let Some(strategy) = config.strategy {
+            self.set_probe(strategy.probe());
+        }
+    }
+
+    /// Moves the cursor and forgets the handed out ports.
+    pub(crate) fn set_cursor(&self, cursor: u16) {
+        self.cursor.store(u32::from(cursor), Ordering::Relaxed);
+        self.issued.clear();
+        self.released().clear();
+        self.leases().clear();
+    }
+
+    /// Puts a handed out port into the free list, so it is reused before scanning further. Ends
+    /// the lease of the port, if any.
+    pub(crate) fn release(&self, port: u16) {
+        self.leases().remove(&port);
+        if self.issued.remove(port) {
+            self.released().push_back(port);
+        }
+    }
+
+    fn released(&self) -> MutexGuard<'_, VecDeque<u16>> {
+        self.released.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn leases(&self) -> MutexGuard<'_, HashMap<u16, LeaseEntry>> {
+        self.leases.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Puts a handed out port under a lease, which expires at `expires`, and is extended by `ttl` on
+    /// renewal. Returns the id of the lease.
+    pub(crate) fn lease(&self, port: u16, ttl: Duration, expires: Instant) -> u64 {
+        let id = self.next_lease_id.fetch_add(1, Ordering::Relaxed);
+        self.leases().insert(port, LeaseEntry { id, ttl, expires });
+        id
+    }
+
+    /// Extends the lease `id` of `port` by its TTL. Fails, if the lease has expired.
+    pub(crate) fn renew(&self, port: u16, id: u64) -> Result<()> {
+        let mut leases = self.leases();
+        match leases.get_mut(&port) {
+            Some(lease) if lease.id == id && lease.expires > Instant::now() => {
+                lease.expires = expiry(lease.ttl)?;
+                Ok(())
+            }
+            _ => Err(Error::LeaseExpired(port)),
+        }
+    }
+
+    /// Ends the lease `id` of `port` and releases the port. Does nothing, if the lease has been
+    /// reclaimed already, as the port may belong to someone else by now.
+    pub(crate) fn end_lease(&self, port: u16, id: u64) {
+        let leases = self.leases();
+        if leases.get(&port).map(|lease| lease.id) == Some(id) {
+            drop(leases);
+            self.release(port);
+        }
+    }
+
+    /// Releases the ports of all expired leases.
+    fn reclaim_expired(&self) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.leases().retain(|port, lease| {
+            let alive = lease.expires > now;
+            if !alive {
+                expired.push(*port);
+            }
+            alive
+        });
+        expired.into_iter().for_each(|port| self.release(port));
+    }
+
+    pub(crate) fn range(&self) -> Range<u16> {
+        let packed = self.range.load(Ordering::Relaxed);
+        (packed >> 16) as u16..packed as u16
+    }
+
+    /// Changes the range, moving the cursor to its start, if the cursor is outside of it.
+    pub(crate) fn set_range(&self, range: Range<u16>) {
+        let packed = u32::from(range.start) << 16 | u32::from(range.end);
+        self.range.store(packed, Ordering::Relaxed);
+        let (start, end) = (u32::from(range.start), u32::from(range.end));
+        let _ = self
+            .cursor
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |position| {
+                Some(start).filter(|_| position < start || position >= end)
+            });
+    }
+
+    pub(crate) fn set_wrap(&self, wrap: WrapPolicy) {
+        self.wrap
+            .store(wrap == WrapPolicy::WrapAround, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_exclude_ephemeral(&self, exclude: bool) {
+        self.exclude_ephemeral.store(exclude, Ordering::Relaxed);
+    }
+
+    pub(crate) fn probe(&self) -> Arc<dyn PortProbe> {
+        Arc::clone(&self.probe.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    pub(crate) fn set_probe(&self, probe: Arc<dyn PortProbe>) {
+        *self.probe.write().unwrap_or_else(PoisonError::into_inner) = probe;
+    }
+
+    fn is_excluded(&self, port: u16) -> bool {
+        self.exclude_ephemeral.load(Ordering::Relaxed) && self.ephemeral.contains(port)
+    }
+
+    /// Maps a cursor position into the range. Returns `None` past the end of the range, unless
+    /// wrapping around.
+    fn port_at(&self, range: &Range<u16>, position: u32) -> Option<u16> {
+        let (start, end) = (u32::from(range.start), u32::from(range.end));
+        if start >= end {
+            return None;
+        }
+        // The cursor may be moved below the range concurrently
+        let position = position.max(start);
+        if self.wrap.load(Ordering::Relaxed) {
+            Some((start + (position - start) % (end - start)) as u16)
+        } else {
+            Some(position as u16).filter(|_| position < end)
+        }
+    }
+
+    /// Claims `port` unless it is handed out or excluded, and releases the claim, if `bind` fails.
+    fn try_claim<T>(
+        &self,
+        port: u16,
+        bind: &mut impl FnMut(u16) -> io::Result<T>,
+    ) -> Option<io::Result<T>> {
+        if self.is_excluded(port) || !self.issued.insert(port) {
+            return None;
+        }
+        let claim = Claim { state: self, port };
+        let result = bind(port);
+        if result.is_ok() {
+            std::mem::forget(claim);
+        }
+        Some(result)
+    }
+
+    /// Binds the first port, which isn't handed out yet or excluded, with `bind`. Released ports
+    /// are tried first, then the range is scanned starting from the cursor.
+    pub(crate) fn allocate<T>(
+        &self,
+        mut bind: impl FnMut(u16) -> io::Result<T>,
+    ) -> Result<(u16, T)> {
+        self.reclaim_expired();
+        let range = self.range();
+        let mut last_error = None;
+
+        loop {
+            let port = match self.released().pop_front() {
+                Some(port) => port,
+                None => break,
+            };
+            if !range.contains(&port) {
+                continue;
+            }
+            match self.try_claim(port, &mut bind) {
+                Some(Ok(bound)) => return Ok((port, bound)),
+                Some(Err(error)) => last_error = Some((port, error)),
+                None => {}
+            }
+        }
+
+        self.cursor
+            .fetch_max(u32::from(range.start), Ordering::Relaxed);
+        // Every port of the range is a candidate at most once per call
+        for _ in range.clone() {
+            let position = self.cursor.fetch_add(1, Ordering::Relaxed);
+            let port = match self.port_at(&range, position) {
+                Some(port) => port,
+                None => break,
+            };
+            match self.try_claim(port, &mut bind) {
+                Some(Ok(bound)) => return Ok((port, bound)),
+                Some(Err(error)) => last_error = Some((port, error)),
+                None => {}
+            }
+        }
+        Err(exhausted(range, last_error))
+    }
+
+    /// Finds `len` consecutive ports, which aren't handed out yet or excluded, and pass `probe`.
+    /// Released ports are not treated specially here, as they are rarely consecutive.
+    pub(crate) fn allocate_block(
+        &self,
+        len: u16,
+        mut probe: impl FnMut(u16) -> io::Result<()>,
+    ) -> Result<Range<u16>> {
+        if len == 0 {
+            return Err(Error::InvalidArgument(
+                "A block must have at least one port".to_owned(),
+            ));
+        }
+        self.reclaim_expired();
+        let range = self.range();
+        let mut last_error = None;
+
+        self.cursor
+            .fetch_max(u32::from(range.start), Ordering::Relaxed);
+        let first = self.cursor.load(Ordering::Relaxed);
+        let mut position = first;
+        // A block may start anywhere in the range, so the whole range is scanned once
+        while position - first < u32::from(range.end.saturating_sub(range.start)) + 1 {
+            let base = match self.port_at(&range, position) {
+                Some(base) => base,
+                None => break,
+            };
+            if u32::from(base) + u32::from(len) > u32::from(range.end) {
+                // The block doesn't fit before the end, so the next candidate is the wrapped
+                // start of the range, if any.
+                position += u32::from(range.end - base);
+                continue;
+            }
+            // Can't overflow, as the block is within the range
+            let block = base..base + len;
+            // Dropping the claims gives the ports up, also if the probe panics
+            let mut claims = Vec::with_capacity(usize::from(len));
+            let unusable = block.clone().find_map(|port| {
+                if self.is_excluded(port) || !self.issued.insert(port) {
+                    return Some((port, None));
+                }
+                claims.push(Claim { state: self, port });
+                probe(port).err().map(|error| (port, Some(error)))
+            });
+            match unusable {
+                Some((port, error)) => {
+                    drop(claims);
+                    last_error = error.map(|error| (port, error)).or(last_error);
+                    position += u32::from(port - base) + 1;
+                }
+                None => {
+                    claims.into_iter().for_each(std::mem::forget);
+                    self.cursor
+                        .fetch_max(position + u32::from(len), Ordering::Relaxed);
+                    return Ok(block);
+                }
+            }
+        }
+        Err(exhausted(range, last_error))
+    }
+}
+
+/// Returns the time `ttl` from now. Fails, if it is too far in the future to be represented.
+pub(crate) fn expiry(ttl: Duration) -> Result<Instant> {
+    Instant::now()
+        .checked_add(ttl)
+        .ok_or_else(|| Error::InvalidArgument(format!("TTL of {:?} is too long", ttl)))
+}
+
+/// Error for an exhausted `range`, which tells who holds the port of the last failed probe.
+fn exhausted(range: Range<u16>, last_error: Option<(u16, io::Error)>) -> Error {
+    Error::RangeExhausted {
+        range,
+        last_error: last_error.map(|(port, error)| crate::holder::annotate(port, error)),
+    }
+}
@@ -0,0 +1,62 @@
+//! Async variants of the allocation functions for the Tokio runtime.
+//!
+//! Probing performs blocking `bind` calls, so it runs on the blocking thread pool. The allocator
+//! is lock-free, so concurrent calls probe in parallel there.
+
+use std::io;
+
+use ::tokio::net::{TcpListener, UdpSocket};
+use ::tokio::task;
+
+use crate::{Error, Result};
+
+async fn spawn_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    match task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+        Err(error) => Err(Error::Io(io::Error::other(error))),
+    }
+}
+
+/// Async version of [`crate::get_unique_free_port`].
+///
+/// # Examples
+/// ```
+/// let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+/// runtime.block_on(async {
+///     let port_1 = unique_port::tokio::get_unique_free_port().await.unwrap();
+///     let port_2 = unique_port::tokio::get_unique_free_port().await.unwrap();
+///     assert_ne!(port_1, port_2);
+/// });
+/// ```
+pub async fn get_unique_free_port() -> Result<u16> {
+    spawn_blocking(crate::get_unique_free_port).await
+}
+
+/// Async version of [`crate::get_unique_free_listener`], which returns a Tokio listener.
+///
+/// # Examples
+/// ```
+/// let runtime = tokio::runtime::Builder::new_current_thread()
+///     .enable_io()
+///     .build()
+///     .unwrap();
+/// runtime.block_on(async {
+///     let listener = unique_port::tokio::get_unique_free_listener().await.unwrap();
+///     assert!(listener.local_addr().unwrap().ip().is_loopback());
+/// });
+/// ```
+pub async fn get_unique_free_listener() -> Result<TcpListener> {
+    let listener = spawn_blocking(crate::get_unique_free_listener).await?;
+    listener.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(listener)?)
+}
+
+/// Async version of [`crate::get_unique_free_udp_socket`], which returns a Tokio socket.
+pub async fn get_unique_free_udp_socket() -> Result<UdpSocket> {
+    let socket = spawn_blocking(crate::get_unique_free_udp_socket).await?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket)?)
+}
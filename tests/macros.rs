@@ -0,0 +1,26 @@
+use std::collections::HashSet;
+
+#[unique_port::test]
+fn injects_one_port_per_argument(p2p: u16, api: u16, telemetry: u16) {
+    let ports = [p2p, api, telemetry]
+        .iter()
+        .copied()
+        .collect::<HashSet<_>>();
+    assert_eq!(3, ports.len());
+}
+
+#[unique_port::test(ports = 1)]
+fn injects_an_array_of_one(ports: [u16; 1]) {
+    assert_ne!(0, ports[0]);
+}
+
+#[unique_port::test]
+fn injects_an_array_of_its_length(ports: [u16; 4]) {
+    let ports = ports.iter().copied().collect::<HashSet<_>>();
+    assert_eq!(4, ports.len());
+}
+
+#[unique_port::test]
+fn injects_a_single_port(port: u16) {
+    assert_ne!(0, port);
+}
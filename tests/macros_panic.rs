@@ -0,0 +1,28 @@
+//! Runs in its own process, as it narrows the range of the global allocator to a single port.
+
+use std::panic;
+use std::sync::atomic::{AtomicU16, Ordering};
+
+static PORT: AtomicU16 = AtomicU16::new(0);
+
+#[unique_port::test]
+#[should_panic(expected = "test failed")]
+#[ignore = "run by `releases_ports_of_panicking_tests`, as it must be the only user of the port"]
+fn panics(port: u16) {
+    PORT.store(port, Ordering::SeqCst);
+    panic!("test failed");
+}
+
+#[test]
+fn releases_ports_of_panicking_tests() {
+    let port = unique_port::get_unique_free_port().unwrap();
+    unique_port::release_port(port).unwrap();
+    unique_port::set_port_range(port..port + 1).unwrap();
+
+    // The second run only gets the port, if the first one gave it back
+    for _ in 0..2 {
+        PORT.store(0, Ordering::SeqCst);
+        assert!(panic::catch_unwind(panics).is_err());
+        assert_eq!(port, PORT.load(Ordering::SeqCst));
+    }
+}